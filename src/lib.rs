@@ -0,0 +1,3181 @@
+use serde::de;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::ops;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::OnceLock;
+use std::thread;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Vec3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32
+}
+
+impl Vec3 {
+    pub fn new(x: f32, y: f32, z: f32) -> Self {
+        Vec3 { x, y, z }
+    }
+
+    pub fn mag(&self) -> f32 {
+        (*self * *self).sqrt()
+    }
+
+    pub fn normalized(&self) -> Self {
+        let mag = self.mag();
+        *self * (1. / mag)
+    }
+
+    pub fn cross(&self, v: &Vec3) -> Self {
+        Vec3::new(self.y * v.z - self.z * v.y, self.z * v.x - self.x * v.z, self.x * v.y - self.y * v.x)
+    }
+
+    // Mirrors self about the line along unit n, so the result points away from the surface when self does.
+    // Kept for existing callers; new code should use reflect, which takes the incoming direction and needs no negating.
+    pub fn reflect_on(&self, n: &Vec3) -> Self {
+        *n * 2. * (*self * *n)  - *self
+    }
+
+    // Bends unit direction self through a surface with unit normal n facing against it, by Snell's law with
+    // eta = (index on the incoming side) / (index on the far side). None on total internal reflection.
+    pub fn refract(&self, n: &Vec3, eta: f32) -> Option<Self> {
+        let cos_i = -(*self * *n);
+        let sin2_t = eta * eta * (1. - cos_i * cos_i);
+        if sin2_t > 1. { return None; }
+        Some(*self * eta + *n * (eta * cos_i - (1. - sin2_t).sqrt()))
+    }
+
+    pub fn origin() -> Self {
+        Vec3::new(0., 0., 0.)
+    }
+
+    // Component-wise product, e.g. for filtering light by a surface color
+    pub fn component_mul(&self, v: &Vec3) -> Self {
+        Vec3::new(self.x * v.x, self.y * v.y, self.z * v.z)
+    }
+
+    // Perceived brightness of a linear RGB color (Rec. 709 weights)
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.x + 0.7152 * self.y + 0.0722 * self.z
+    }
+
+    // True when every component is within epsilon of v's. Prefer this to == for computed vectors, which pick up
+    // rounding error. f32 keeps about 7 significant digits, so 1e-5 suits unit-scale vectors such as normals;
+    // scale epsilon up with the magnitudes being compared.
+    pub fn approx_eq(&self, v: &Vec3, epsilon: f32) -> bool {
+        (self.x - v.x).abs() <= epsilon && (self.y - v.y).abs() <= epsilon && (self.z - v.z).abs() <= epsilon
+    }
+}
+
+// Mirror direction of incident bouncing off a surface with unit normal: incident - 2 (incident . normal) normal.
+// Unit in gives unit out up to rounding, and the normal may face either way.
+pub fn reflect(incident: Vec3, normal: Vec3) -> Vec3 {
+    incident - normal * (2. * (incident * normal))
+}
+
+// Written as the compact array [x, y, z]; read from either that or an object {"x": .., "y": .., "z": ..}
+impl Serialize for Vec3 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        [self.x, self.y, self.z].serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Vec3 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(Vec3Visitor)
+    }
+}
+
+struct Vec3Visitor;
+
+impl<'de> de::Visitor<'de> for Vec3Visitor {
+    type Value = Vec3;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array [x, y, z] or an object {x, y, z}")
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Vec3, A::Error> {
+        let mut c = [0.; 3];
+        for (i, c) in c.iter_mut().enumerate() {
+            *c = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(i, &self))?;
+        }
+        if seq.next_element::<de::IgnoredAny>()?.is_some() {
+            return Err(de::Error::invalid_length(4, &self));
+        }
+        Ok(Vec3::new(c[0], c[1], c[2]))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Vec3, A::Error> {
+        const FIELDS: &[&str] = &["x", "y", "z"];
+        let mut c = [None; 3];
+        while let Some(key) = map.next_key::<String>()? {
+            let i = FIELDS.iter().position(|f| *f == key).ok_or_else(|| de::Error::unknown_field(&key, FIELDS))?;
+            if c[i].is_some() { return Err(de::Error::duplicate_field(FIELDS[i])); }
+            c[i] = Some(map.next_value()?);
+        }
+        let field = |i: usize| c[i].ok_or_else(|| de::Error::missing_field(FIELDS[i]));
+        Ok(Vec3::new(field(0)?, field(1)?, field(2)?))
+    }
+}
+
+impl ops::Index<usize> for Vec3 {
+    type Output = f32;
+    fn index(&self, i: usize) -> &f32 {
+        match i {
+            0 => &self.x,
+            1 => &self.y,
+            _ => &self.z,
+        }
+    }
+}
+
+impl ops::Mul<Vec3> for Vec3 {
+    type Output = f32;
+    fn mul(self, v: Vec3) -> f32 {
+        self.x * v.x + self.y * v.y + self.z * v.z // Dot product
+    }
+}
+
+impl ops::Mul<f32> for Vec3 {
+    type Output = Vec3;
+    fn mul(self, f: f32) -> Self {
+        Vec3 { x: f * self.x, y: f * self.y, z: f * self.z }
+    }
+}
+
+impl ops::Add<Vec3> for Vec3 {
+    type Output = Vec3;
+    fn add(self, v: Vec3) -> Self {
+        Vec3 { x: self.x + v.x, y: self.y + v.y, z: self.z + v.z }
+    }
+}
+
+impl ops::Sub<Vec3> for Vec3 {
+    type Output = Vec3;
+    fn sub(self, v: Vec3) -> Self {
+        Vec3 { x: self.x - v.x, y: self.y - v.y, z: self.z - v.z }
+    }
+}
+
+impl ops::Neg for Vec3 {
+    type Output = Self;
+    fn neg(self) -> Self {
+        Vec3 { x: -self.x, y: -self.y, z: -self.z }
+    }
+}
+
+// Row-major 3x3 matrix, used to rotate directions and normals
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Mat3 {
+    pub rows: [Vec3; 3],
+}
+
+impl Mat3 {
+    pub fn new(r0: Vec3, r1: Vec3, r2: Vec3) -> Self {
+        Mat3 { rows: [r0, r1, r2] }
+    }
+
+    pub fn identity() -> Self {
+        Mat3::new(Vec3::new(1., 0., 0.), Vec3::new(0., 1., 0.), Vec3::new(0., 0., 1.))
+    }
+
+    // Rotation of `angle` radians counter-clockwise about `axis` (Rodrigues' formula)
+    pub fn from_axis_angle(axis: &Vec3, angle: f32) -> Self {
+        let a = axis.normalized();
+        let (s, c) = angle.sin_cos();
+        let t = 1. - c;
+        Mat3::new(
+            Vec3::new(t * a.x * a.x + c, t * a.x * a.y - s * a.z, t * a.x * a.z + s * a.y),
+            Vec3::new(t * a.x * a.y + s * a.z, t * a.y * a.y + c, t * a.y * a.z - s * a.x),
+            Vec3::new(t * a.x * a.z - s * a.y, t * a.y * a.z + s * a.x, t * a.z * a.z + c),
+        )
+    }
+
+    // Rotation about x, then y, then z (angles in radians)
+    pub fn from_euler(x: f32, y: f32, z: f32) -> Self {
+        Mat3::from_axis_angle(&Vec3::new(0., 0., 1.), z)
+            * Mat3::from_axis_angle(&Vec3::new(0., 1., 0.), y)
+            * Mat3::from_axis_angle(&Vec3::new(1., 0., 0.), x)
+    }
+
+    pub fn col(&self, i: usize) -> Vec3 {
+        Vec3::new(self.rows[0][i], self.rows[1][i], self.rows[2][i])
+    }
+
+    // For a rotation, the transpose is also the inverse
+    pub fn transposed(&self) -> Self {
+        Mat3::new(self.col(0), self.col(1), self.col(2))
+    }
+}
+
+impl ops::Mul<Vec3> for Mat3 {
+    type Output = Vec3;
+    fn mul(self, v: Vec3) -> Vec3 {
+        Vec3::new(self.rows[0] * v, self.rows[1] * v, self.rows[2] * v)
+    }
+}
+
+impl ops::Mul<Mat3> for Mat3 {
+    type Output = Mat3;
+    fn mul(self, m: Mat3) -> Self { // Composition: (self * m) * v == self * (m * v)
+        let (c0, c1, c2) = (m.col(0), m.col(1), m.col(2));
+        let row = |r: Vec3| Vec3::new(r * c0, r * c1, r * c2);
+        Mat3::new(row(self.rows[0]), row(self.rows[1]), row(self.rows[2]))
+    }
+}
+
+// Rotation quaternion w + xi + yj + zk, stored as scalar part `w` and vector part `v`
+#[derive(Debug, Copy, Clone)]
+pub struct Quat {
+    pub w: f32,
+    pub v: Vec3,
+}
+
+impl Quat {
+    pub fn new(w: f32, v: Vec3) -> Self {
+        Quat { w, v }
+    }
+
+    pub fn identity() -> Self {
+        Quat::new(1., Vec3::origin())
+    }
+
+    // Rotation of `angle` radians counter-clockwise about `axis`
+    pub fn from_axis_angle(axis: &Vec3, angle: f32) -> Self {
+        let (s, c) = (angle / 2.).sin_cos();
+        Quat::new(c, axis.normalized() * s)
+    }
+
+    pub fn dot(&self, q: &Quat) -> f32 {
+        self.w * q.w + self.v * q.v
+    }
+
+    pub fn normalized(&self) -> Self {
+        let mag = self.dot(self).sqrt();
+        Quat::new(self.w / mag, self.v * (1. / mag))
+    }
+
+    pub fn conjugate(&self) -> Self {
+        Quat::new(self.w, -self.v)
+    }
+
+    // Rotates v by this (unit) quaternion: q * v * q^-1
+    pub fn rotate(&self, v: &Vec3) -> Vec3 {
+        let t = self.v.cross(v) * 2.;
+        *v + t * self.w + self.v.cross(&t)
+    }
+
+    // Spherical interpolation from self (t = 0) to q (t = 1) along the shortest arc
+    pub fn slerp(&self, q: &Quat, t: f32) -> Self {
+        let mut q = *q;
+        let mut cos_theta = self.dot(&q);
+        if cos_theta < 0. { // q and -q are the same rotation; take the short way round
+            q = Quat::new(-q.w, -q.v);
+            cos_theta = -cos_theta;
+        }
+        if cos_theta > 0.9995 { // Nearly parallel, fall back to lerp to avoid dividing by ~0
+            return Quat::new(self.w + (q.w - self.w) * t, self.v + (q.v - self.v) * t).normalized();
+        }
+        let theta = cos_theta.acos();
+        let a = ((1. - t) * theta).sin() / theta.sin();
+        let b = (t * theta).sin() / theta.sin();
+        Quat::new(self.w * a + q.w * b, self.v * a + q.v * b)
+    }
+
+    pub fn to_mat3(self) -> Mat3 {
+        Mat3::new(
+            self.rotate(&Vec3::new(1., 0., 0.)),
+            self.rotate(&Vec3::new(0., 1., 0.)),
+            self.rotate(&Vec3::new(0., 0., 1.)),
+        ).transposed()
+    }
+}
+
+impl ops::Mul<Quat> for Quat {
+    type Output = Quat;
+    fn mul(self, q: Quat) -> Self { // Hamilton product: rotates by q, then by self
+        Quat::new(self.w * q.w - self.v * q.v, q.v * self.w + self.v * q.w + self.v.cross(&q.v))
+    }
+}
+
+pub struct Ray {
+    pub origin: Vec3,
+    pub dir: Vec3,
+}
+
+impl Ray {
+    // Point at parameter t; a distance along the ray when dir is unit length
+    pub fn at(&self, t: f32) -> Vec3 {
+        self.origin + self.dir * t
+    }
+}
+
+pub struct RaycastHit {
+    pub distance: f32, // Along the ray to point
+    pub index: usize, // Of the hit sphere in Scene::spheres, for debugging
+    pub point: Vec3,
+    pub normal: Vec3, // Shading normal; outward unless the material is two-sided, when it faces back against the ray
+    pub front_face: bool, // Ray arrived from outside the sphere
+    pub tangents: Option<(Vec3, Vec3)>, // Unit tangent and bitangent, only filled in for anisotropic materials
+    pub mat: Material,
+}
+
+// Emits color * intensity, so brightness can change without shifting hue
+pub struct PointLight {
+    pub origin: Vec3,
+    pub color: Vec3, // Hue, with components in [0, 1]
+    pub intensity: f32,
+    // Size of the ball shadows are cast from, softening their edges into penumbrae; 0 casts hard shadows.
+    // Shading otherwise still treats the light as a point at origin.
+    pub radius: f32,
+}
+
+impl PointLight {
+    // White light with hard shadows
+    pub fn new(origin: Vec3, intensity: f32) -> Self {
+        PointLight { origin, color: Vec3::new(1., 1., 1.), intensity, radius: 0. }
+    }
+
+    pub fn with_color(mut self, color: Vec3) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn with_radius(mut self, radius: f32) -> Self {
+        self.radius = radius;
+        self
+    }
+
+    pub fn radiance(&self) -> Vec3 {
+        self.color * self.intensity
+    }
+}
+
+// In scene data every field is optional, defaulting as in MaterialBuilder
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Material { 
+    pub color: Vec3,
+    pub phong_exp: f32,
+    pub phong_const: f32,
+    pub diffuse_const: f32,
+    pub ambient_const: f32,
+    pub reflectance: f32,
+    pub reflection_blur: f32, // Spreads reflections over a cone up to 90 degrees at 1 for a frosted look; 0 is a sharp mirror
+    // Further bounces a reflection off this surface may take, lowering (never raising) RenderSettings::max_depth.
+    // 1 shows reflected surfaces without their own reflections; 0 reflects only the background.
+    pub max_reflection_depth: Option<u32>,
+    pub refraction_index: f32, // 1.0 for air or anything not refractive, about 1.33 for water and 1.5 for glass
+    // Beer-Lambert attenuation per unit distance for light crossing the inside, per channel; zero is clear.
+    // Only seen through surfaces with alpha below 1.
+    pub absorption: Vec3,
+    // Light given off by the surface itself. Always visible, directly and in reflections; it only illuminates other
+    // surfaces when path tracing, where emissive spheres are sampled as lights. Shaded mode lights with point lights only.
+    pub emission: Vec3,
+    pub alpha: f32, // Opacity; below 1 the surface is blended with what's seen through it, refracted by refraction_index. Shadows stay opaque.
+    pub pattern: Option<Pattern>, // Procedural color varying over the surface, used in place of color when set
+    pub two_sided: bool, // Inside hits are shaded like outside ones; otherwise they keep the outward normal and mostly go dark
+    pub metallic: bool, // Reflections are tinted by color, as for gold or copper; otherwise reflected untinted like a dielectric
+    pub bump: Option<BumpMap>,
+    pub anisotropy: Option<Anisotropy>, // Replaces the Phong highlight with a stretched one when set
+}
+
+impl Material {
+    pub fn blank() -> Self {
+        Material { color: Vec3::new(0., 0., 0.), phong_exp: 0., phong_const: 0., diffuse_const: 0., ambient_const: 0., reflectance: 0., reflection_blur: 0., max_reflection_depth: None, refraction_index: 1., absorption: Vec3::new(0., 0., 0.), emission: Vec3::new(0., 0., 0.), alpha: 1., pattern: None, two_sided: false, metallic: false, bump: None, anisotropy: None }
+    }
+
+    pub fn new(color: Vec3, phong_exp: f32, phong_const: f32, diffuse_const: f32, ambient_const: f32, reflectance: f32, refraction_index: f32) -> Self {
+        Material { color, phong_exp, phong_const, diffuse_const, ambient_const, reflectance, reflection_blur: 0., max_reflection_depth: None, refraction_index, absorption: Vec3::new(0., 0., 0.), emission: Vec3::new(0., 0., 0.), alpha: 1., pattern: None, two_sided: false, metallic: false, bump: None, anisotropy: None }
+    }
+
+    // Sets phong_exp from a perceptual 0-1 slider: 0 is a broad sheen (exponent 2), 1 a pinpoint highlight (4096).
+    // The exponent doubles every 1/11 of the range, so equal steps look like equal changes in highlight size.
+    pub fn with_shininess(mut self, shininess: f32) -> Self {
+        self.phong_exp = 2f32.powf(1. + 11. * shininess.clamp(0., 1.));
+        self
+    }
+
+    pub fn with_reflection_blur(mut self, reflection_blur: f32) -> Self {
+        self.reflection_blur = reflection_blur;
+        self
+    }
+
+    pub fn with_max_reflection_depth(mut self, max_reflection_depth: u32) -> Self {
+        self.max_reflection_depth = Some(max_reflection_depth);
+        self
+    }
+
+    pub fn with_absorption(mut self, absorption: Vec3) -> Self {
+        self.absorption = absorption;
+        self
+    }
+
+    pub fn with_emission(mut self, emission: Vec3) -> Self {
+        self.emission = emission;
+        self
+    }
+
+    pub fn with_alpha(mut self, alpha: f32) -> Self {
+        self.alpha = alpha;
+        self
+    }
+
+    pub fn with_pattern(mut self, pattern: Pattern) -> Self {
+        self.pattern = Some(pattern);
+        self
+    }
+
+    // Base color at a world-space surface point
+    pub fn color_at(&self, point: &Vec3) -> Vec3 {
+        match &self.pattern {
+            Some(pattern) => pattern.color_at(point),
+            None => self.color,
+        }
+    }
+
+    pub fn with_two_sided(mut self) -> Self {
+        self.two_sided = true;
+        self
+    }
+
+    pub fn with_metallic(mut self) -> Self {
+        self.metallic = true;
+        self
+    }
+
+    pub fn with_anisotropy(mut self, roughness_u: f32, roughness_v: f32) -> Self {
+        self.anisotropy = Some(Anisotropy { roughness_u, roughness_v });
+        self
+    }
+
+    pub fn with_bump(mut self, texture: usize, strength: f32) -> Self {
+        self.bump = Some(BumpMap { texture, strength });
+        self
+    }
+
+    pub fn builder() -> MaterialBuilder {
+        MaterialBuilder::new()
+    }
+}
+
+// Named alternative to Material::new's positional arguments. Starts as a matte mid-gray with a little ambient
+// light, no highlight and no reflection, so only the properties that differ need setting.
+#[derive(Debug, Copy, Clone)]
+pub struct MaterialBuilder {
+    mat: Material,
+}
+
+impl MaterialBuilder {
+    pub fn new() -> Self {
+        let mut mat = Material::blank();
+        mat.color = Vec3::new(0.5, 0.5, 0.5);
+        mat.diffuse_const = 1.;
+        mat.ambient_const = 0.1;
+        mat.phong_exp = 32.;
+        MaterialBuilder { mat }
+    }
+
+    pub fn color(mut self, color: Vec3) -> Self {
+        self.mat.color = color;
+        self
+    }
+
+    pub fn diffuse(mut self, diffuse_const: f32) -> Self {
+        self.mat.diffuse_const = diffuse_const;
+        self
+    }
+
+    pub fn ambient(mut self, ambient_const: f32) -> Self {
+        self.mat.ambient_const = ambient_const;
+        self
+    }
+
+    // Strength of the highlight, phong_const
+    pub fn specular(mut self, phong_const: f32) -> Self {
+        self.mat.phong_const = phong_const;
+        self
+    }
+
+    // Highlight size on the 0-1 scale of Material::with_shininess
+    pub fn shininess(mut self, shininess: f32) -> Self {
+        self.mat = self.mat.with_shininess(shininess);
+        self
+    }
+
+    // Highlight size as the raw Phong exponent, in place of shininess
+    pub fn phong_exp(mut self, phong_exp: f32) -> Self {
+        self.mat.phong_exp = phong_exp;
+        self
+    }
+
+    pub fn reflectance(mut self, reflectance: f32) -> Self {
+        self.mat.reflectance = reflectance;
+        self
+    }
+
+    pub fn refraction_index(mut self, refraction_index: f32) -> Self {
+        self.mat.refraction_index = refraction_index;
+        self
+    }
+
+    pub fn build(self) -> Material {
+        self.mat
+    }
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        MaterialBuilder::new().build()
+    }
+}
+
+impl Default for MaterialBuilder {
+    fn default() -> Self {
+        MaterialBuilder::new()
+    }
+}
+
+// Procedural surface color evaluated at world-space points
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum Pattern {
+    Checker { even: Vec3, odd: Vec3, scale: f32 }, // 3D checkerboard of cubes with edge length `scale`
+    Noise { low: Vec3, high: Vec3, scale: f32, perlin: Perlin }, // Blend of low and high by Perlin noise with features about `scale` across
+    // Veins of `vein` color through `base`, as bands sin(x / scale + distortion * turbulence) warped by noise.
+    // Smaller scale packs veins closer; more distortion makes them wilder, and more octaves adds finer wiggles.
+    Marble { base: Vec3, vein: Vec3, scale: f32, distortion: f32, octaves: u32, perlin: Perlin },
+    // Growth rings: cylinders ring_spacing apart around the line through center along axis, shading from light
+    // to dark and back across each ring. distortion pushes the rings in and out by noise, in ring widths.
+    Wood { light: Vec3, dark: Vec3, center: Vec3, axis: Vec3, ring_spacing: f32, distortion: f32, perlin: Perlin },
+}
+
+impl Pattern {
+    pub fn color_at(&self, p: &Vec3) -> Vec3 {
+        match self {
+            Pattern::Checker { even, odd, scale } => {
+                let parity = (p.x / scale).floor() + (p.y / scale).floor() + (p.z / scale).floor();
+                if parity.rem_euclid(2.) == 0. { *even } else { *odd }
+            }
+            Pattern::Noise { low, high, scale, perlin } => {
+                let t = 0.5 * (perlin.noise(&(*p * (1. / scale))) + 1.);
+                *low * (1. - t) + *high * t
+            }
+            Pattern::Marble { base, vein, scale, distortion, octaves, perlin } => {
+                let q = *p * (1. / scale);
+                let t = 0.5 * (1. - (q.x + distortion * perlin.turbulence(&q, *octaves)).sin()); // 1 at the vein centers
+                *base * (1. - t) + *vein * t
+            }
+            Pattern::Wood { light, dark, center, axis, ring_spacing, distortion, perlin } => {
+                let d = *p - *center;
+                let a = axis.normalized();
+                let radius = (d - a * (d * a)).mag() / ring_spacing;
+                let rings = radius + distortion * perlin.noise(&(*p * (0.25 / ring_spacing))); // Wobbles span a few rings
+                let t = 0.5 * (1. - (2. * std::f32::consts::PI * rings).cos()); // 1 midway between ring boundaries
+                *light * (1. - t) + *dark * t
+            }
+        }
+    }
+}
+
+// Shuffled 0-255 shared by every Perlin generator, built on first use
+fn perlin_permutation() -> &'static [u8; 256] {
+    static PERM: OnceLock<[u8; 256]> = OnceLock::new();
+    PERM.get_or_init(|| {
+        let mut perm = [0u8; 256];
+        for (i, p) in perm.iter_mut().enumerate() { *p = i as u8; }
+        let mut rng = Rng::new(0);
+        for i in (1..perm.len()).rev() { // Fisher-Yates shuffle
+            perm.swap(i, (rng.next_u64() % (i as u64 + 1)) as usize);
+        }
+        perm
+    })
+}
+
+// Improved Perlin gradient noise, repeating every 256 units along each axis. The permutation table is shared and
+// the seed picks where the lattice indexes into it, keeping the generator small enough to live in a Material.
+// Written in scene data as just its seed.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(from = "u64")]
+pub struct Perlin {
+    offset: [usize; 3],
+}
+
+impl Perlin {
+    // The same seed always gives the same noise
+    pub fn new(seed: u64) -> Self {
+        let h = mix(seed) as usize;
+        Perlin { offset: [h & 255, (h >> 8) & 255, (h >> 16) & 255] }
+    }
+
+    // Smoothly varying value in about [-1, 1], zero at integer lattice points
+    pub fn noise(&self, p: &Vec3) -> f32 {
+        let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let (x, y, z) = (p.x - fx, p.y - fy, p.z - fz);
+        // Wrapped by the & 255 below
+        let xi = (fx as i64 as usize).wrapping_add(self.offset[0]);
+        let yi = (fy as i64 as usize).wrapping_add(self.offset[1]);
+        let zi = (fz as i64 as usize).wrapping_add(self.offset[2]);
+        let perm = perlin_permutation();
+        let hash = |i: usize, j: usize, k: usize| {
+            let h = perm[i & 255] as usize;
+            let h = perm[(h + j) & 255] as usize;
+            perm[(h + k) & 255]
+        };
+        // Dot product of the corner's gradient with the offset from that corner
+        let corner = |di: usize, dj: usize, dk: usize| {
+            let (ox, oy, oz) = (x - di as f32, y - dj as f32, z - dk as f32);
+            let h = hash(xi.wrapping_add(di), yi.wrapping_add(dj), zi.wrapping_add(dk)) & 15;
+            // One of the 12 cube edge directions, with 4 repeated to fill the 16 cases
+            let u = if h < 8 { ox } else { oy };
+            let v = if h < 4 { oy } else if h == 12 || h == 14 { ox } else { oz };
+            (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+        };
+        let fade = |t: f32| t * t * t * (t * (t * 6. - 15.) + 10.);
+        let lerp = |t: f32, a: f32, b: f32| a + t * (b - a);
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+        lerp(w,
+            lerp(v, lerp(u, corner(0, 0, 0), corner(1, 0, 0)), lerp(u, corner(0, 1, 0), corner(1, 1, 0))),
+            lerp(v, lerp(u, corner(0, 0, 1), corner(1, 0, 1)), lerp(u, corner(0, 1, 1), corner(1, 1, 1))))
+    }
+
+    // Sum of |noise| over octaves, each at double the frequency and half the amplitude of the last
+    pub fn turbulence(&self, p: &Vec3, octaves: u32) -> f32 {
+        let mut sum = 0.;
+        let (mut frequency, mut amplitude) = (1., 1.);
+        for _ in 0..octaves {
+            sum += self.noise(&(*p * frequency)).abs() * amplitude;
+            frequency *= 2.;
+            amplitude *= 0.5;
+        }
+        sum
+    }
+}
+
+impl From<u64> for Perlin {
+    fn from(seed: u64) -> Self {
+        Perlin::new(seed)
+    }
+}
+
+// Ward anisotropic specular lobe, e.g. for brushed metal. Roughness is the slope spread along the surface
+// tangent (direction of increasing u) and bitangent; the highlight stretches along the rougher direction.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub struct Anisotropy {
+    pub roughness_u: f32,
+    pub roughness_v: f32,
+}
+
+impl Anisotropy {
+    // Specular reflectance toward `view` for light arriving from `light`, both unit vectors pointing away from the surface
+    pub fn ward(&self, light: &Vec3, view: &Vec3, normal: &Vec3, tangent: &Vec3, bitangent: &Vec3) -> f32 {
+        let n_l = *light * *normal;
+        let n_v = *view * *normal;
+        if n_l <= 0. || n_v <= 0. { return 0.; }
+        let half = (*light + *view).normalized();
+        let (h_t, h_b, h_n) = (half * *tangent / self.roughness_u, half * *bitangent / self.roughness_v, half * *normal);
+        let exponent = -(h_t * h_t + h_b * h_b) / (h_n * h_n);
+        exponent.exp() / (4. * std::f32::consts::PI * self.roughness_u * self.roughness_v * (n_l * n_v).sqrt()) * n_l
+    }
+}
+
+// Heightmap that perturbs the shading normal; brighter texels are higher
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub struct BumpMap {
+    pub texture: usize, // Index into Scene::textures; a bump map naming a texture the scene lacks is skipped
+    pub strength: f32, // Normal tilt per unit of height change between neighbouring texels
+}
+
+// Rays passing closer than this fraction of radius^2 to tangent miss the sphere. Within it the squared half chord
+// is down at the rounding error of the f32 subtraction that produces it, so whether such a ray hit would be noise;
+// cutting it keeps silhouettes and shadow edges from speckling. Primary, reflection and shadow rays all share it.
+const GRAZE_TOLERANCE: f32 = 1e-6;
+
+pub struct Sphere {
+    pub center: Vec3,
+    pub radius: f32,
+    pub mat: Material,
+    // Turns the texture mapping on the surface, taking local axes to world space: uv and tangents are worked out
+    // from the local +y pole and -z seam, which land along orientation * +y and orientation * -z
+    pub orientation: Mat3,
+}
+
+impl Sphere {
+    pub fn new(center: Vec3, radius: f32, mat: Material) -> Self {
+        Sphere { center, radius, mat, orientation: Mat3::identity() }
+    }
+
+    pub fn with_orientation(mut self, orientation: Mat3) -> Self {
+        self.orientation = orientation;
+        self
+    }
+
+    // Returns the nearest intersection distance along ray beyond t_min, or None for no intersection
+    pub fn intersects_ray(&self, ray: &Ray, t_min: f32) -> Option<f32> {
+        let (t_0, t_1) = self.ray_roots(ray)?;
+        if t_0 > t_min { Some(t_0) } else if t_1 > t_min { Some(t_1) } else { None }
+    }
+
+    // Distances along the line of ray where it enters and leaves the sphere, nearest first; either may be behind
+    // the origin. None if the line misses or only grazes the sphere (within GRAZE_TOLERANCE): a tangent line has no
+    // chord through it and its normal lies across the ray, so it's counted as a miss rather than a hit of zero width.
+    // Solved as t^2 + 2bt + c = 0 with b = oc . dir and c = oc . oc - radius^2 for unit dir. The root nearer zero is
+    // taken as c / q rather than -b plus a nearly equal square root, which would cancel; for big spheres like the
+    // 7000 unit ground that puts hits several times closer to exact than measuring along the ray to the center.
+    pub fn ray_roots(&self, ray: &Ray) -> Option<(f32, f32)> {
+        let dir = ray.dir.normalized();
+        let oc = ray.origin - self.center;
+        let b = oc * dir;
+        let c = oc * oc - self.radius.powi(2);
+        let discriminant = b * b - c;
+        if discriminant <= self.radius.powi(2) * GRAZE_TOLERANCE { return None; }
+        let q = -b - discriminant.sqrt().copysign(b);
+        let (t_a, t_b) = (c / q, q);
+        Some((t_a.min(t_b), t_a.max(t_b)))
+    }
+
+    // Spherical texture coordinates of a surface point, in the sphere's local frame: u runs from 0 at the -z seam
+    // through 0.25 at -x, 0.5 at +z and 0.75 at +x (counter-clockwise seen from above), and v from 0 at the +y pole
+    // to 1 at the -y pole
+    pub fn uv(&self, point: &Vec3) -> (f32, f32) {
+        let n = self.orientation.transposed() * (*point - self.center).normalized();
+        let u = 0.5 + n.x.atan2(n.z) / (2. * std::f32::consts::PI);
+        let v = n.y.clamp(-1., 1.).acos() / std::f32::consts::PI;
+        (u, v)
+    }
+
+    // Unit surface directions of increasing u and v at a point, falling back to an arbitrary frame at the poles
+    pub fn tangents(&self, point: &Vec3) -> (Vec3, Vec3) {
+        let n = (*point - self.center).normalized();
+        let local = self.orientation.transposed() * n;
+        let t = Vec3::new(local.z, 0., -local.x);
+        let t = self.orientation * if t.mag() > 1e-6 { t.normalized() } else { Vec3::new(1., 0., 0.) };
+        (t, t.cross(&n))
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        let r = Vec3::new(self.radius, self.radius, self.radius);
+        Aabb { min: self.center - r, max: self.center + r }
+    }
+}
+
+// Sphere as written in scene data, e.g. {"center": [0, 1, -5], "radius": 1, "material": "red"}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SphereDesc {
+    pub center: Vec3,
+    pub radius: f32,
+    pub material: MaterialRef,
+    #[serde(default = "Mat3::identity")]
+    pub orientation: Mat3, // Sphere::orientation, as rows
+}
+
+impl SphereDesc {
+    // Looks a named material up in the scene's shared table
+    pub fn resolve(&self, materials: &HashMap<String, Material>) -> Result<Sphere, UndefinedMaterial> {
+        let mat = match &self.material {
+            MaterialRef::Inline(mat) => *mat,
+            MaterialRef::Named(name) => *materials.get(name).ok_or_else(|| UndefinedMaterial(name.clone()))?,
+        };
+        Ok(Sphere::new(self.center, self.radius, mat).with_orientation(self.orientation))
+    }
+}
+
+// A material written out in full, or the name of one in the shared table so it can be reused across spheres
+#[derive(Debug, Clone, PartialEq)]
+pub enum MaterialRef {
+    Inline(Material),
+    Named(String),
+}
+
+impl<'de> Deserialize<'de> for MaterialRef {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(MaterialRefVisitor)
+    }
+}
+
+struct MaterialRefVisitor;
+
+impl<'de> de::Visitor<'de> for MaterialRefVisitor {
+    type Value = MaterialRef;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a material or the name of one")
+    }
+
+    fn visit_str<E: de::Error>(self, name: &str) -> Result<MaterialRef, E> {
+        Ok(MaterialRef::Named(name.to_string()))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, map: A) -> Result<MaterialRef, A::Error> {
+        Material::deserialize(de::value::MapAccessDeserializer::new(map)).map(MaterialRef::Inline)
+    }
+}
+
+// A sphere named a material missing from the shared table
+#[derive(Debug, Clone, PartialEq)]
+pub struct UndefinedMaterial(pub String);
+
+impl fmt::Display for UndefinedMaterial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "undefined material \"{}\"", self.0)
+    }
+}
+
+impl Error for UndefinedMaterial {}
+
+// Axis-aligned bounding box
+#[derive(Debug, Copy, Clone)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn union(&self, b: &Aabb) -> Self {
+        Aabb {
+            min: Vec3::new(self.min.x.min(b.min.x), self.min.y.min(b.min.y), self.min.z.min(b.min.z)),
+            max: Vec3::new(self.max.x.max(b.max.x), self.max.y.max(b.max.y), self.max.z.max(b.max.z)),
+        }
+    }
+
+    // Returns the (entry, exit) distances along ray using the slab method, or None for a miss
+    pub fn intersects_ray(&self, ray: &Ray) -> Option<(f32, f32)> {
+        let mut t_enter = f32::MIN;
+        let mut t_exit = f32::MAX;
+        for i in 0..3 {
+            let inv = 1. / ray.dir[i];
+            let t_a = (self.min[i] - ray.origin[i]) * inv;
+            let t_b = (self.max[i] - ray.origin[i]) * inv;
+            t_enter = t_enter.max(t_a.min(t_b));
+            t_exit = t_exit.min(t_a.max(t_b));
+        }
+        if t_enter <= t_exit && t_exit > 0. { Some((t_enter, t_exit)) } else { None }
+    }
+}
+
+// Uniform grid of voxels over the scene bounds, each listing the spheres whose bounds overlap it
+pub struct Grid {
+    bounds: Aabb,
+    resolution: usize, // Cells along each axis
+    cell_size: Vec3,
+    cells: Vec<Vec<usize>>,
+}
+
+impl Grid {
+    // None when there are no spheres to bound. A resolution of 0 is taken as 1.
+    pub fn new(spheres: &[Sphere], resolution: usize) -> Option<Self> {
+        let resolution = resolution.max(1);
+        let bounds = spheres.iter().map(|s| s.bounds()).reduce(|a, b| a.union(&b))?;
+        let extent = bounds.max - bounds.min;
+        let n = resolution as f32;
+        let cell_size = Vec3::new(extent.x / n, extent.y / n, extent.z / n);
+        let mut grid = Grid { bounds, resolution, cell_size, cells: vec![Vec::new(); resolution.pow(3)] };
+
+        for (i, sphere) in spheres.iter().enumerate() {
+            // Pad slightly so rounding never drops a sphere from a cell its surface touches
+            let b = sphere.bounds();
+            let pad = cell_size * 1e-4;
+            let lo = grid.cell_of(&(b.min - pad));
+            let hi = grid.cell_of(&(b.max + pad));
+            for z in lo[2]..=hi[2] {
+                for y in lo[1]..=hi[1] {
+                    for x in lo[0]..=hi[0] {
+                        let idx = grid.index(x, y, z);
+                        grid.cells[idx].push(i);
+                    }
+                }
+            }
+        }
+        Some(grid)
+    }
+
+    fn index(&self, x: usize, y: usize, z: usize) -> usize {
+        (z * self.resolution + y) * self.resolution + x
+    }
+
+    // Cell coordinates containing point p, clamped to the grid
+    fn cell_of(&self, p: &Vec3) -> [usize; 3] {
+        let mut cell = [0; 3];
+        for (i, c) in cell.iter_mut().enumerate() {
+            let f = ((p[i] - self.bounds.min[i]) / self.cell_size[i]).floor();
+            *c = (f.max(0.) as usize).min(self.resolution - 1);
+        }
+        cell
+    }
+
+    // Walks the cells pierced by ray in order (3D-DDA), returning the nearest sphere index and distance beyond t_min
+    pub fn nearest_hit(&self, ray: &Ray, spheres: &[Sphere], t_min: f32) -> Option<(usize, f32)> {
+        let (t_enter, _) = self.bounds.intersects_ray(ray)?;
+        let t_start = t_enter.max(0.);
+        let mut cell = self.cell_of(&ray.at(t_start));
+
+        let mut step = [0i64; 3];
+        let mut t_next = [f32::MAX; 3];
+        let mut t_delta = [f32::MAX; 3];
+        for i in 0..3 {
+            if ray.dir[i] > 0. {
+                step[i] = 1;
+                let boundary = self.bounds.min[i] + (cell[i] + 1) as f32 * self.cell_size[i];
+                t_next[i] = (boundary - ray.origin[i]) / ray.dir[i];
+                t_delta[i] = self.cell_size[i] / ray.dir[i];
+            } else if ray.dir[i] < 0. {
+                step[i] = -1;
+                let boundary = self.bounds.min[i] + cell[i] as f32 * self.cell_size[i];
+                t_next[i] = (boundary - ray.origin[i]) / ray.dir[i];
+                t_delta[i] = -self.cell_size[i] / ray.dir[i];
+            }
+        }
+
+        let mut nearest = None;
+        loop {
+            let candidates = &self.cells[self.index(cell[0], cell[1], cell[2])];
+            nearest = nearest_sphere(ray, spheres, candidates.iter().copied(), nearest, t_min);
+
+            // Any hit closer than the far side of this cell can't be beaten by later cells
+            let axis = if t_next[0] < t_next[1] {
+                if t_next[0] < t_next[2] { 0 } else { 2 }
+            } else if t_next[1] < t_next[2] { 1 } else { 2 };
+            if let Some((_, dist)) = nearest {
+                if dist <= t_next[axis] { break; }
+            }
+
+            let next = cell[axis] as i64 + step[axis];
+            if step[axis] == 0 || next < 0 || next >= self.resolution as i64 { break; }
+            cell[axis] = next as usize;
+            t_next[axis] += t_delta[axis];
+        }
+        nearest
+    }
+}
+
+// Image of linear RGB texels, (0, 0) at the top-left
+pub struct Texture {
+    pub width: usize,
+    pub height: usize,
+    pub texels: Vec<Vec3>,
+    pub filter: TextureFilter,
+    pub wrap: WrapMode,
+    pub mips: Vec<MipLevel>, // Successive halvings of texels down to 1x1, built by new(); stale if texels change later
+}
+
+// A downsampled copy of a texture's texels
+pub struct MipLevel {
+    pub width: usize,
+    pub height: usize,
+    pub texels: Vec<Vec3>,
+}
+
+// Which texel stands in for one past the edge, for texture coordinates outside [0, 1]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum WrapMode {
+    Repeat, // Tiles the texture
+    Clamp, // Extends the edge texels outward
+    Mirror, // Tiles with every other copy flipped, so neighbouring copies meet without a seam
+}
+
+impl WrapMode {
+    // Texel index in 0..n for texel i of the endless plane
+    pub fn resolve(&self, i: i64, n: usize) -> usize {
+        let n = n as i64;
+        (match self {
+            WrapMode::Repeat => i.rem_euclid(n),
+            WrapMode::Clamp => i.clamp(0, n - 1),
+            WrapMode::Mirror => {
+                let m = i.rem_euclid(2 * n);
+                if m < n { m } else { 2 * n - 1 - m }
+            }
+        }) as usize
+    }
+}
+
+// How sample() turns texels into a color between texel centers
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TextureFilter {
+    Nearest, // The texel under (u, v), blocky up close but exact per texel
+    Bilinear, // Blend of the four texels around (u, v) by distance
+    // Bilinear from the two mip levels whose texels are nearest the size of the area being sampled, blended between.
+    // Keeps distant or grazing surfaces from shimmering; Bilinear is the same filter with mipmapping turned off.
+    Trilinear,
+}
+
+impl Texture {
+    // Trilinear filtering and repeat wrapping, with mip levels built from texels
+    pub fn new(width: usize, height: usize, texels: Vec<Vec3>) -> Self {
+        let mut mips: Vec<MipLevel> = Vec::new();
+        let (mut w, mut h) = (width, height);
+        while w > 1 || h > 1 {
+            let src = mips.last().map_or(&texels[..], |level| &level.texels[..]);
+            let (half_w, half_h) = (w.div_ceil(2), h.div_ceil(2));
+            let mut half = Vec::with_capacity(half_w * half_h);
+            for y in 0..half_h {
+                for x in 0..half_w {
+                    // Averages the 2x2 block, or what's left of it at an odd edge
+                    let (xs, ys) = (2 * x..(2 * x + 2).min(w), 2 * y..(2 * y + 2).min(h));
+                    let n = (xs.len() * ys.len()) as f32;
+                    let sum = ys.flat_map(|sy| xs.clone().map(move |sx| src[sy * w + sx])).fold(Vec3::origin(), |a, b| a + b);
+                    half.push(sum * (1. / n));
+                }
+            }
+            mips.push(MipLevel { width: half_w, height: half_h, texels: half });
+            (w, h) = (half_w, half_h);
+        }
+        Texture { width, height, texels, filter: TextureFilter::Trilinear, wrap: WrapMode::Repeat, mips }
+    }
+
+    // Mip level k as (width, height, texels), 0 being the full-size texture
+    fn level(&self, k: usize) -> (usize, usize, &[Vec3]) {
+        match k {
+            0 => (self.width, self.height, &self.texels),
+            k => (self.mips[k - 1].width, self.mips[k - 1].height, &self.mips[k - 1].texels),
+        }
+    }
+
+    // Fractional mip level for sampling an area `footprint` wide in texture coordinates (1 being the whole
+    // texture), where one level's texel about covers it. Always 0 unless the filter is Trilinear.
+    pub fn lod(&self, footprint: f32) -> f32 {
+        match self.filter {
+            TextureFilter::Trilinear => (footprint * self.width.max(self.height) as f32).max(1.).log2().min(self.mips.len() as f32),
+            _ => 0.,
+        }
+    }
+
+    // Reads a plain (P3) or binary (P6) PPM file
+    pub fn load_ppm(path: &str) -> std::io::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {}", path, msg));
+
+        // Header is four whitespace-separated tokens, with '#' comments running to end of line
+        let mut tokens = Vec::new();
+        let mut pos = 0;
+        while tokens.len() < 4 && pos < bytes.len() {
+            if bytes[pos] == b'#' {
+                while pos < bytes.len() && bytes[pos] != b'\n' { pos += 1; }
+            } else if bytes[pos].is_ascii_whitespace() {
+                pos += 1;
+            } else {
+                let start = pos;
+                while pos < bytes.len() && !bytes[pos].is_ascii_whitespace() { pos += 1; }
+                tokens.push(String::from_utf8_lossy(&bytes[start..pos]).into_owned());
+            }
+        }
+        if tokens.len() < 4 { return Err(invalid("truncated header")); }
+        let num = |t: &str| t.parse::<usize>().map_err(|_| invalid("bad header value"));
+        let (width, height, max_val) = (num(&tokens[1])?, num(&tokens[2])?, num(&tokens[3])? as f32);
+        if width == 0 || height == 0 { return Err(invalid("width and height must be positive")); }
+        let len = width.checked_mul(height).and_then(|n| n.checked_mul(3)).ok_or_else(|| invalid("image too large"))?;
+
+        let values: Vec<f32> = match tokens[0].as_str() {
+            "P3" => String::from_utf8_lossy(&bytes[pos..]).split_whitespace()
+                .map(|t| t.parse::<f32>().map_err(|_| invalid("bad pixel value")))
+                .collect::<Result<_, _>>()?,
+            // Single whitespace byte precedes the data, which may be missing entirely in a truncated file
+            "P6" if max_val < 256. => bytes.get(pos + 1..).unwrap_or(&[]).iter().map(|&b| b as f32).collect(),
+            _ => return Err(invalid("unsupported PPM format")),
+        };
+        if values.len() < len { return Err(invalid("truncated pixel data")); }
+
+        let texels = values.chunks(3).take(width * height)
+            .map(|c| Vec3::new(c[0] / max_val, c[1] / max_val, c[2] / max_val))
+            .collect();
+        Ok(Texture::new(width, height, texels))
+    }
+
+    // Color at (u, v) through self.filter at full resolution, with (0, 0) and (1, 1) the outer corners of the
+    // corner texels; self.wrap decides what lies outside that square
+    pub fn sample(&self, u: f32, v: f32) -> Vec3 {
+        self.sample_footprint(u, v, 0.)
+    }
+
+    // sample() averaged over an area about `footprint` wide in texture coordinates, as set out in lod()
+    pub fn sample_footprint(&self, u: f32, v: f32, footprint: f32) -> Vec3 {
+        match self.filter {
+            TextureFilter::Nearest => self.sample_nearest(u, v),
+            TextureFilter::Bilinear => self.sample_bilinear(u, v),
+            TextureFilter::Trilinear => {
+                let lod = self.lod(footprint);
+                let (k, t) = (lod.floor() as usize, lod.fract());
+                let fine = self.bilinear_level(k, u, v);
+                if t == 0. { fine } else { fine * (1. - t) + self.bilinear_level(k + 1, u, v) * t }
+            }
+        }
+    }
+
+    // Nearest texel to (u, v)
+    pub fn sample_nearest(&self, u: f32, v: f32) -> Vec3 {
+        let x = self.wrap.resolve((u * self.width as f32).floor() as i64, self.width);
+        let y = self.wrap.resolve((v * self.height as f32).floor() as i64, self.height);
+        self.texels[y * self.width + x]
+    }
+
+    // Weighted blend of the four texels whose centers surround (u, v)
+    pub fn sample_bilinear(&self, u: f32, v: f32) -> Vec3 {
+        self.bilinear_level(0, u, v)
+    }
+
+    fn bilinear_level(&self, k: usize, u: f32, v: f32) -> Vec3 {
+        let (width, height, texels) = self.level(k);
+        let (fx, fy) = (u * width as f32 - 0.5, v * height as f32 - 0.5);
+        let (x0, y0) = (fx.floor(), fy.floor());
+        let (tx, ty) = (fx - x0, fy - y0);
+        let texel = |x: f32, y: f32| texels[self.wrap.resolve(y as i64, height) * width + self.wrap.resolve(x as i64, width)];
+        let top = texel(x0, y0) * (1. - tx) + texel(x0 + 1., y0) * tx;
+        let bottom = texel(x0, y0 + 1.) * (1. - tx) + texel(x0 + 1., y0 + 1.) * tx;
+        top * (1. - ty) + bottom * ty
+    }
+}
+
+// Color returned for rays that escape the scene, for camera rays and reflections alike
+pub enum Background {
+    Ramp, // Grayscale by ray height, the original look
+    Solid(Vec3),
+    Gradient { horizon: Vec3, zenith: Vec3 }, // Blends by ray height, horizon color at and below the horizon
+    Environment(Texture), // Equirectangular (latitude-longitude) map, -z at the center column
+    Sky { horizon: Vec3, zenith: Vec3, sun: Sun }, // Gradient with a sun disk
+    // Tuned approximation of a clear daytime sky (in the spirit of Preetham, not a fit to it): blue overhead and
+    // paler at the horizon, warming toward orange as the sun sets, with a glow around the sun and a sun disk
+    PhysicalSky { sun: Sun },
+}
+
+// Sun disk drawn into the sky, which can also light the scene as a directional light from the same direction
+#[derive(Debug, Copy, Clone)]
+pub struct Sun {
+    pub direction: Vec3, // Toward the sun
+    pub angular_radius: f32, // In radians; the real sun's is about 0.0047
+    pub color: Vec3,
+    pub intensity: f32, // Brightness of the visible disk, color * intensity
+    pub light: Option<f32>, // Intensity of the directional light it casts, or None to only draw the disk
+}
+
+impl Sun {
+    pub fn covers(&self, dir: &Vec3) -> bool {
+        *dir * self.direction.normalized() >= self.angular_radius.cos()
+    }
+}
+
+// Sky color in unit direction dir with the sun toward unit vector sun, for Background::PhysicalSky
+fn physical_sky(dir: &Vec3, sun: &Vec3) -> Vec3 {
+    let smoothstep = |lo: f32, hi: f32, x: f32| {
+        let t = ((x - lo) / (hi - lo)).clamp(0., 1.);
+        t * t * (3. - 2. * t)
+    };
+    let elevation = sun.y;
+    let day = smoothstep(-0.1, 0.2, elevation); // Fades the whole sky toward night as the sun drops below the horizon
+    let low_sun = 1. - smoothstep(0., 0.35, elevation); // Reddens the horizon near sunrise and sunset
+
+    let zenith = Vec3::new(0.18, 0.36, 0.8);
+    let horizon = Vec3::new(0.7, 0.8, 0.92) * (1. - low_sun) + Vec3::new(1., 0.55, 0.3) * low_sun;
+    let t = dir.y.max(0.).sqrt(); // Stays near the horizon color for a band above it, as haze does
+    let sky = horizon * (1. - t) + zenith * t;
+
+    // Forward scattering: a broad haze around the sun plus a tighter bright halo, warmer when the sun is low
+    let cos_sun = (*dir * *sun).max(0.);
+    let glow_color = Vec3::new(1., 0.9, 0.7) * (1. - low_sun) + Vec3::new(1., 0.5, 0.2) * low_sun;
+    let glow = glow_color * (0.35 * cos_sun.powi(8) + 0.8 * cos_sun.powi(64));
+
+    let below = if dir.y < 0. { 1. + dir.y.max(-0.5) } else { 1. }; // Darkens under the horizon, down to half
+    (sky + glow) * (day * below)
+}
+
+// Fixed environment lookups per ambient estimate; a deterministic spiral keeps the result free of noise
+const SKY_AMBIENT_SAMPLES: usize = 16;
+
+impl Background {
+    // Cosine-weighted average of an environment map over the hemisphere around normal, used as ambient light.
+    // Other backgrounds return None and shading keeps the flat ambient term. Occlusion isn't considered.
+    pub fn ambient(&self, normal: &Vec3) -> Option<Vec3> {
+        if let Background::Environment(_) = self {
+            let (t, b) = orthonormal_basis(normal);
+            let golden_angle = std::f32::consts::PI * (3. - 5f32.sqrt());
+            let mut sum = Vec3::origin();
+            for k in 0..SKY_AMBIENT_SAMPLES {
+                // Spiral points spread evenly over the unit disk, projected up onto the hemisphere
+                let u = (k as f32 + 0.5) / SKY_AMBIENT_SAMPLES as f32;
+                let (r, phi) = (u.sqrt(), k as f32 * golden_angle);
+                let dir = t * (r * phi.cos()) + b * (r * phi.sin()) + *normal * (1. - u).sqrt();
+                sum = sum + self.color(&dir);
+            }
+            Some(sum * (1. / SKY_AMBIENT_SAMPLES as f32))
+        } else {
+            None
+        }
+    }
+
+    pub fn color(&self, dir: &Vec3) -> Vec3 {
+        self.beam_color(dir, 0.)
+    }
+
+    // color() for a ray standing for a beam that widens by `spread` radians, so an environment map is filtered over
+    // the beam's width: spread / 2 pi of the map's width, which spans 2 pi of longitude
+    pub fn beam_color(&self, dir: &Vec3, spread: f32) -> Vec3 {
+        match self {
+            Background::Ramp => Vec3::new(dir.y, dir.y, dir.y),
+            Background::Solid(color) => *color,
+            Background::Gradient { horizon, zenith } => {
+                let t = dir.y.max(0.);
+                *horizon * (1. - t) + *zenith * t
+            }
+            Background::Environment(map) => {
+                let u = 0.5 + dir.x.atan2(-dir.z) / (2. * std::f32::consts::PI);
+                let v = dir.y.clamp(-1., 1.).acos() / std::f32::consts::PI;
+                map.sample_footprint(u, v, spread / (2. * std::f32::consts::PI))
+            }
+            Background::Sky { horizon, zenith, sun } => {
+                if sun.covers(dir) { return sun.color * sun.intensity; }
+                let t = dir.y.max(0.);
+                *horizon * (1. - t) + *zenith * t
+            }
+            Background::PhysicalSky { sun } => {
+                if sun.covers(dir) { return sun.color * sun.intensity; }
+                physical_sky(dir, &sun.direction.normalized())
+            }
+        }
+    }
+
+    pub fn sun(&self) -> Option<&Sun> {
+        match self {
+            Background::Sky { sun, .. } | Background::PhysicalSky { sun } => Some(sun),
+            _ => None,
+        }
+    }
+}
+
+// Haze filling the scene. Each ray's hit color is blended toward the fog color by how far the ray traveled,
+// so reflections are fogged over their own path too. Rays that escape to the background are left clear.
+#[derive(Debug, Copy, Clone)]
+pub enum Fog {
+    Exponential { density: f32, color: Vec3 }, // Fog fraction 1 - e^(-density * distance); density 0 is clear
+    // Clear up to near, rising linearly to solid fog color at far and beyond; easier to art-direct than Exponential.
+    // far <= near is a hard step, clear before near and solid from it on.
+    Linear { near: f32, far: f32, color: Vec3 },
+}
+
+impl Fog {
+    pub fn apply(&self, color: Vec3, distance: f32) -> Vec3 {
+        match self {
+            Fog::Exponential { density, color: fog_color } => {
+                let t = 1. - (-density * distance).exp();
+                color * (1. - t) + *fog_color * t
+            }
+            Fog::Linear { near, far, color: fog_color } => {
+                let t = if far <= near {
+                    if distance < *near { 0. } else { 1. }
+                } else {
+                    ((distance - near) / (far - near)).clamp(0., 1.)
+                };
+                color * (1. - t) + *fog_color * t
+            }
+        }
+    }
+}
+
+// Highlight shape for non-anisotropic materials, chosen for the whole scene
+#[derive(Debug, Copy, Clone)]
+pub enum SpecularModel {
+    Phong, // Reflected light direction against the view direction
+    Blinn, // Half vector against the normal; gives wider highlights, roughly matching Phong at 4x the exponent
+}
+
+pub struct Scene {
+    pub spheres: Vec<Sphere>,
+    pub lights: Vec<PointLight>,
+    pub background: Background,
+    pub specular_model: SpecularModel,
+    pub textures: Vec<Texture>, // Referenced by index from materials
+    pub grid: Option<Grid>, // Acceleration structure; brute force over all spheres when None
+    pub fog: Option<Fog>,
+}
+
+impl Scene {
+    // No spheres or lights, a Ramp background and Phong highlights
+    pub fn new() -> Self {
+        Scene {
+            spheres: Vec::new(),
+            lights: Vec::new(),
+            background: Background::Ramp,
+            specular_model: SpecularModel::Phong,
+            textures: Vec::new(),
+            grid: None,
+            fog: None,
+        }
+    }
+
+    // Drops any grid, which would no longer cover every sphere; call build_grid again once the scene is complete
+    pub fn add_sphere(&mut self, sphere: Sphere) {
+        self.spheres.push(sphere);
+        self.grid = None;
+    }
+
+    pub fn add_light(&mut self, light: PointLight) {
+        self.lights.push(light);
+    }
+
+    // Buckets the spheres into a uniform grid with `resolution` cells along each axis (at least 1). An empty scene
+    // is left without one, since brute force over no spheres costs nothing.
+    pub fn build_grid(&mut self, resolution: usize) {
+        self.grid = Grid::new(&self.spheres, resolution);
+    }
+
+    // Every surface crossing ahead of ray, entering and leaving each sphere, nearest first with ties going to the
+    // lower sphere index, e.g. for sorting transparent layers or combining spheres as solids. The first is the
+    // same hit scene_hit finds with a t_min of 0 and no draw distance. Tests every sphere rather than walking the grid.
+    // Crossings are found and sorted up front, but each RaycastHit is only filled in as the iterator reaches it.
+    pub fn intersect_all(&self, ray: Ray) -> impl Iterator<Item = RaycastHit> + '_ {
+        let mut crossings: Vec<(usize, f32)> = self.spheres.iter().enumerate()
+            .filter_map(|(i, sphere)| sphere.ray_roots(&ray).map(|(t_0, t_1)| [(i, t_0), (i, t_1)]))
+            .flatten()
+            .filter(|&(_, t)| t > 0.)
+            .collect();
+        crossings.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+        crossings.into_iter().filter_map(move |nearest| hit_info(&ray, self, Some(nearest), f32::INFINITY, 0.))
+    }
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Scene::new()
+    }
+}
+
+// Tests candidate spheres against ray, returning the nearest (index, distance) beyond t_min including `nearest` so
+// far. Ties go to the lowest index so the result doesn't depend on the order candidates are visited in.
+fn nearest_sphere(ray: &Ray, spheres: &[Sphere], candidates: impl Iterator<Item = usize>, nearest: Option<(usize, f32)>,
+                  t_min: f32) -> Option<(usize, f32)> {
+    let mut nearest = nearest;
+    for i in candidates {
+        if let Some(cur_dist) = spheres[i].intersects_ray(ray, t_min) {
+            let closer = match nearest {
+                Some((min_i, min_dist)) => cur_dist < min_dist || (cur_dist == min_dist && i < min_i),
+                None => true,
+            };
+            if closer { nearest = Some((i, cur_dist)); }
+        }
+    }
+    nearest
+}
+
+// Tilts normal against the heightmap's gradient, taken by finite differences one texel apart in u and v
+// of the mip level picked for footprint, scaled back to a full-size texel's worth so the strength doesn't change
+fn bump_normal(bump: &BumpMap, heightmap: &Texture, sphere: &Sphere, point: &Vec3, normal: &Vec3, footprint: f32) -> Vec3 {
+    let (u, v) = sphere.uv(point);
+    let texel = heightmap.lod(footprint).exp2();
+    let (du, dv) = (texel / heightmap.width as f32, texel / heightmap.height as f32);
+    let height = |u: f32, v: f32| heightmap.sample_footprint(u.rem_euclid(1.), v, footprint).luminance();
+    let h = height(u, v);
+    let dh_du = (height(u + du, v) - h) / texel;
+    let dh_dv = (height(u, v + dv) - h) / texel;
+    let (t, b) = sphere.tangents(point);
+    (*normal - (t * dh_du + b * dh_dv) * bump.strength).normalized()
+}
+
+// Returns RaycastHit with info for the nearest intersection between t_min and max_dist, or None if there is none
+pub fn scene_hit(ray: &Ray, scene: &Scene, t_min: f32, max_dist: f32) -> Option<RaycastHit> {
+    beam_hit(ray, scene, t_min, max_dist, 0.)
+}
+
+// scene_hit for a ray standing for a beam that widens by `spread` per unit distance, which picks the texture detail
+// seen at the hit
+fn beam_hit(ray: &Ray, scene: &Scene, t_min: f32, max_dist: f32, spread: f32) -> Option<RaycastHit> {
+    let nearest = match &scene.grid {
+        Some(grid) => grid.nearest_hit(ray, &scene.spheres, t_min),
+        None => nearest_sphere(ray, &scene.spheres, 0..scene.spheres.len(), None, t_min),
+    };
+    hit_info(ray, scene, nearest, max_dist, spread)
+}
+
+// beam_hit for a camera ray, testing only the spheres in `visible` when given
+fn primary_hit(ray: &Ray, scene: &Scene, visible: Option<&[usize]>, t_min: f32, max_dist: f32, spread: f32) -> Option<RaycastHit> {
+    match visible {
+        Some(visible) => {
+            let nearest = nearest_sphere(ray, &scene.spheres, visible.iter().copied(), None, t_min);
+            hit_info(ray, scene, nearest, max_dist, spread)
+        }
+        None => beam_hit(ray, scene, t_min, max_dist, spread),
+    }
+}
+
+// Fills in the RaycastHit for the nearest (index, distance) found along ray, if it's within max_dist
+fn hit_info(ray: &Ray, scene: &Scene, nearest: Option<(usize, f32)>, max_dist: f32, spread: f32) -> Option<RaycastHit> {
+    match nearest {
+        Some((i, min_dist)) if min_dist < max_dist => { // Max draw distance
+            let sphere = &scene.spheres[i];
+            let surface_point = ray.at(min_dist);
+            let outward = (surface_point - sphere.center).normalized();
+            let front_face = ray.dir * outward < 0.;
+            let mut surface_normal = outward;
+            // Scene data can name any texture index, so a missing one leaves the surface flat rather than panicking
+            if let Some((bump, heightmap)) = sphere.mat.bump.and_then(|b| scene.textures.get(b.texture).map(|t| (b, t))) {
+                // Beam width over the texture's span across the sphere, ignoring foreshortening
+                let footprint = spread * min_dist / (std::f32::consts::PI * sphere.radius);
+                surface_normal = bump_normal(&bump, heightmap, sphere, &surface_point, &surface_normal, footprint);
+            }
+            if !front_face && sphere.mat.two_sided { surface_normal = -surface_normal; }
+            let tangents = sphere.mat.anisotropy.map(|_| {
+                // Re-orthogonalize against the (possibly bumped) shading normal
+                let (t, _) = sphere.tangents(&surface_point);
+                let t = (t - surface_normal * (t * surface_normal)).normalized();
+                (t, t.cross(&surface_normal))
+            });
+            Some(RaycastHit {
+                distance: min_dist,
+                index: i,
+                point: surface_point,
+                normal: surface_normal,
+                front_face,
+                tangents,
+                mat: sphere.mat,
+            })
+        }
+        _ => None,
+    }
+}
+
+// Rays cast by one render, by kind. Each worker counts its own and they're summed when the render finishes.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct RayStats {
+    pub primary: u64,
+    pub shadow: u64,
+    pub reflection: u64,
+    pub transmission: u64,
+    pub diffuse: u64,
+}
+
+impl RayStats {
+    pub fn total(&self) -> u64 {
+        self.primary + self.shadow + self.reflection + self.transmission + self.diffuse
+    }
+}
+
+impl ops::AddAssign<RayStats> for RayStats {
+    fn add_assign(&mut self, other: RayStats) {
+        self.primary += other.primary;
+        self.shadow += other.shadow;
+        self.reflection += other.reflection;
+        self.transmission += other.transmission;
+        self.diffuse += other.diffuse;
+    }
+}
+
+// Recursion limits and randomness for one camera ray's tree of secondary rays
+struct PathState<'a> {
+    shadow_samples: u32, // From RenderSettings::shadow_samples
+    t_min: f32, // From RenderSettings::t_min
+    max_distance: f32, // From RenderSettings::max_distance
+    max_depth: u32, // Bounces of any kind (reflection or diffuse) before rays return the background
+    rng: &'a mut Rng,
+    rays: &'a mut RayStats, // The calling worker's counts, added to as rays are cast
+    // Path trace: diffuse surfaces gather indirect light in place of the flat ambient term
+    global_illumination: bool,
+    gi_bounces: u32, // Most diffuse bounces a path may take
+    diffuse_depth: u32, // Diffuse bounces taken so far to reach the current ray
+    // The current ray is a diffuse bounce whose origin already sampled the emissive spheres directly, so emission it
+    // finds must not be counted twice
+    emitters_sampled: bool,
+    covered: bool, // The camera ray hit geometry, for the alpha channel
+    visible: Option<&'a [usize]>, // Spheres the camera ray is tested against, from View::visible
+    spread: f32, // From View::spread
+}
+
+impl<'a> PathState<'a> {
+    fn new(settings: &RenderSettings, rng: &'a mut Rng, rays: &'a mut RayStats, global_illumination: bool) -> Self {
+        PathState { shadow_samples: settings.shadow_samples, t_min: settings.t_min, max_distance: settings.max_distance, max_depth: settings.max_depth, rng, rays, global_illumination, gi_bounces: settings.gi_bounces, diffuse_depth: 0, emitters_sampled: false, covered: false, visible: None, spread: 0. }
+    }
+}
+
+// Diffuse bounces that always continue before Russian roulette may end a path
+const ROULETTE_AFTER_BOUNCES: u32 = 2;
+
+// Cast a ray and return the pixel color as a Vec3
+fn raycast(ray: &Ray, scene: &Scene, depth: u32, path: &mut PathState) -> Vec3 {
+    let emitters_sampled = std::mem::replace(&mut path.emitters_sampled, false);
+    if depth < path.max_depth {
+        let hit = if depth == 0 {
+            primary_hit(ray, scene, path.visible, path.t_min, path.max_distance, path.spread)
+        } else {
+            beam_hit(ray, scene, path.t_min, path.max_distance, path.spread)
+        };
+        if let Some(hit_info) = hit {
+            if depth == 0 { path.covered = true; }
+            let surface_mat = hit_info.mat;
+            let surface_point = hit_info.point;
+            let surface_normal = hit_info.normal;
+            let mut diffuse_light = Vec3::origin();
+            let mut specular_light = Vec3::origin();
+
+            // Reflect
+            let mut reflect_dir = reflect(ray.dir, surface_normal).normalized();
+            if surface_mat.reflection_blur > 0. {
+                // Frosted: jitter within a cone, folding any direction that dips below the surface back above it
+                let half_angle = surface_mat.reflection_blur.min(1.) * std::f32::consts::FRAC_PI_2;
+                reflect_dir = random_cone_direction(&reflect_dir, half_angle.cos(), path.rng);
+                let below = reflect_dir * surface_normal;
+                if below < 0. { reflect_dir = reflect_dir - surface_normal * (2. * below); }
+            }
+            let reflect_point = surface_point + surface_normal * path.t_min;
+            path.rays.reflection += 1;
+            let max_depth = path.max_depth;
+            if let Some(limit) = surface_mat.max_reflection_depth {
+                path.max_depth = max_depth.min((depth + 1).saturating_add(limit));
+            }
+            let reflect_color = raycast(&Ray { origin: reflect_point, dir: reflect_dir }, scene, depth + 1, path);
+            path.max_depth = max_depth;
+
+            // (direction, distance, radiance, point light) of each light, the sun being infinitely far away
+            let point_lights = scene.lights.iter().map(|light| {
+                let light_vec = light.origin - surface_point;
+                (light_vec.normalized(), light_vec.mag(), light.radiance(), Some(light))
+            });
+            let sun_light = scene.background.sun()
+                .and_then(|sun| sun.light.map(|intensity| (sun.direction.normalized(), f32::MAX, sun.color * intensity, None)));
+            for (light_dir, light_dist, radiance, point_light) in point_lights.chain(sun_light) {
+                // Fraction of the light not in shadow, from shadow rays to random points in its ball if it has one
+                let shadow_point = surface_point + surface_normal * path.t_min;
+                let visibility = match point_light {
+                    Some(light) if light.radius > 0. => {
+                        let samples = path.shadow_samples.max(1);
+                        let lit = (0..samples).filter(|_| {
+                            let to_light = light.origin + random_in_ball(path.rng) * light.radius - shadow_point;
+                            path.rays.shadow += 1;
+                            let shadow_ray = Ray { origin: shadow_point, dir: to_light.normalized() };
+                            scene_hit(&shadow_ray, scene, path.t_min, to_light.mag()).is_none()
+                        }).count();
+                        lit as f32 / samples as f32
+                    }
+                    _ => {
+                        path.rays.shadow += 1;
+                        if scene_hit(&Ray { origin: shadow_point, dir: light_dir }, scene, path.t_min, light_dist).is_some() { 0. } else { 1. }
+                    }
+                };
+                if visibility == 0. { continue; }
+                let radiance = radiance * visibility;
+
+                diffuse_light = diffuse_light + radiance * (light_dir * surface_normal).max(0.);
+                let specular = match (surface_mat.anisotropy, hit_info.tangents) {
+                    (Some(aniso), Some((t, b))) => aniso.ward(&light_dir, &-ray.dir, &surface_normal, &t, &b),
+                    _ => match scene.specular_model {
+                        // Negated before powf so fractional exponents, as from with_shininess, don't meet a negative base
+                        SpecularModel::Phong => (-(light_dir.reflect_on(&surface_normal) * ray.dir)).max(0.).powf(surface_mat.phong_exp),
+                        SpecularModel::Blinn => {
+                            let half = (light_dir - ray.dir).normalized();
+                            (half * surface_normal).max(0.).powf(surface_mat.phong_exp)
+                        }
+                    },
+                };
+                specular_light = specular_light + radiance * specular;
+            }
+
+            let surface_color = surface_mat.color_at(&surface_point);
+            let diffuse_color = surface_color.component_mul(&diffuse_light) * surface_mat.diffuse_const;
+            let specular_color = specular_light * surface_mat.phong_const;
+            let ambient_color = if !path.global_illumination {
+                match scene.background.ambient(&surface_normal) {
+                    Some(sky) => surface_color.component_mul(&sky) * surface_mat.ambient_const,
+                    None => surface_color * surface_mat.ambient_const,
+                }
+            } else {
+                let albedo = surface_color * surface_mat.diffuse_const;
+                emitted_light(scene, &surface_point, &surface_normal, &albedo, hit_info.index, path)
+                    + indirect_diffuse(scene, &surface_point, &surface_normal, &albedo, depth, path)
+            };
+            let reflect_color = if surface_mat.metallic { reflect_color.component_mul(&surface_color) } else { reflect_color };
+            let reflection = reflect_color * surface_mat.reflectance;
+            let mut color = diffuse_color + specular_color + ambient_color + reflection;
+            if !emitters_sampled { color = color + surface_mat.emission; }
+            if surface_mat.alpha < 1. {
+                // Partially transparent: blend with whatever is seen through the surface. Entering the sphere the ray
+                // bends by 1 / refraction_index and leaving by refraction_index; past the critical angle on the way
+                // out it reflects back inside instead (total internal reflection).
+                let facing = if ray.dir * surface_normal < 0. { surface_normal } else { -surface_normal };
+                let eta = if hit_info.front_face { 1. / surface_mat.refraction_index } else { surface_mat.refraction_index };
+                let through_dir = match ray.dir.refract(&facing, eta) {
+                    Some(dir) => dir.normalized(),
+                    None => reflect(ray.dir, facing).normalized(),
+                };
+                path.rays.transmission += 1;
+                // Stepped along the new direction since the normal may face either way
+                let through_point = surface_point + through_dir * path.t_min;
+                let through = raycast(&Ray { origin: through_point, dir: through_dir }, scene, depth + 1, path);
+                color = color * surface_mat.alpha + through * (1. - surface_mat.alpha);
+            }
+            if !hit_info.front_face {
+                // Hit from inside, so the light traveled hit_info.distance through this sphere's medium rather than fog
+                let a = surface_mat.absorption * -hit_info.distance;
+                return color.component_mul(&Vec3::new(a.x.exp(), a.y.exp(), a.z.exp()));
+            }
+            return match scene.fog {
+                Some(fog) => fog.apply(color, hit_info.distance),
+                None => color,
+            };
+        }
+    }
+
+    scene.background.beam_color(&ray.dir, path.spread)
+}
+
+// Light arriving at a diffuse surface from one cosine-weighted bounce, filtered by albedo.
+// The Lambertian cos / pi cancels against the sampling PDF. After ROULETTE_AFTER_BOUNCES diffuse bounces, Russian
+// roulette ends the path with probability 1 - max(albedo) and scales survivors up to stay unbiased. Roulette can
+// end a path well before gi_bounces runs out; gi_bounces is only the hard cap.
+fn indirect_diffuse(scene: &Scene, point: &Vec3, normal: &Vec3, albedo: &Vec3, depth: u32, path: &mut PathState) -> Vec3 {
+    if path.diffuse_depth >= path.gi_bounces { return Vec3::origin(); }
+
+    let mut weight = *albedo;
+    if path.diffuse_depth >= ROULETTE_AFTER_BOUNCES {
+        let survive = albedo.x.max(albedo.y).max(albedo.z).min(1.);
+        if path.rng.next_f32() >= survive { return Vec3::origin(); }
+        weight = weight * (1. / survive);
+    }
+
+    let bounce_dir = random_cosine_direction(normal, path.rng);
+    path.rays.diffuse += 1;
+    path.diffuse_depth += 1;
+    path.emitters_sampled = true;
+    let incoming = raycast(&Ray { origin: *point + *normal * path.t_min, dir: bounce_dir }, scene, depth + 1, path);
+    path.diffuse_depth -= 1;
+    incoming.component_mul(&weight)
+}
+
+// Direct light reaching a diffuse surface from the emissive spheres other than its own, one shadow ray each.
+// Directions are sampled uniformly over the cone each sphere subtends, which the Lambertian BRDF albedo / pi and
+// the cone's PDF 1 / (2 pi (1 - cos_max)) turn into a weight of 2 (1 - cos_max) cos.
+fn emitted_light(scene: &Scene, point: &Vec3, normal: &Vec3, albedo: &Vec3, hit_index: usize, path: &mut PathState) -> Vec3 {
+    let mut light = Vec3::origin();
+    for (i, emitter) in scene.spheres.iter().enumerate() {
+        if i == hit_index || emitter.mat.emission == Vec3::origin() { continue; }
+        let to_center = emitter.center - *point;
+        let dist = to_center.mag();
+        if dist <= emitter.radius { continue; }
+        let cos_max = (1. - (emitter.radius / dist).powi(2)).sqrt();
+        let dir = random_cone_direction(&(to_center * (1. / dist)), cos_max, path.rng);
+        let cos = dir * *normal;
+        if cos <= 0. { continue; }
+
+        path.rays.shadow += 1;
+        match scene_hit(&Ray { origin: *point + *normal * path.t_min, dir }, scene, path.t_min, path.max_distance) {
+            Some(hit) if hit.index == i => light = light + emitter.mat.emission * (2. * (1. - cos_max) * cos),
+            _ => {}
+        }
+    }
+    light.component_mul(albedo)
+}
+
+// Small xorshift64* PRNG; the same seed always yields the same sequence
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: mix(seed) | 1 } // State must be nonzero
+    }
+
+    // Independent stream for pixel (x, y), hashed from both coordinates so a pixel draws the same numbers however
+    // the image is split between threads or tiles
+    pub fn for_pixel(seed: u64, x: usize, y: usize) -> Self {
+        Rng::new(seed ^ mix(((y as u64) << 32) ^ x as u64))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // Uniform in [0, 1)
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+// SplitMix64 finalizer, scrambles an integer into a well-distributed hash
+fn mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// Van der Corput radical inverse of i in the given base, in [0, 1)
+pub fn radical_inverse(mut i: u64, base: u64) -> f32 {
+    let inv_base = 1. / base as f64;
+    let mut inv = inv_base;
+    let mut result = 0.;
+    while i > 0 {
+        result += (i % base) as f64 * inv;
+        i /= base;
+        inv *= inv_base;
+    }
+    result as f32
+}
+
+// Two unit vectors perpendicular to unit vector n and each other (Duff et al. 2017)
+pub fn orthonormal_basis(n: &Vec3) -> (Vec3, Vec3) {
+    let sign = 1f32.copysign(n.z);
+    let a = -1. / (sign + n.z);
+    let b = n.x * n.y * a;
+    (Vec3::new(1. + sign * n.x * n.x * a, sign * b, -sign * n.x), Vec3::new(b, sign + n.y * n.y * a, -n.y))
+}
+
+// Random unit direction in the hemisphere around unit normal, with density proportional to cos(theta)
+pub fn random_cosine_direction(normal: &Vec3, rng: &mut Rng) -> Vec3 {
+    // Uniform point on the unit disk, projected up onto the hemisphere (Malley's method)
+    let (u1, u2) = (rng.next_f32(), rng.next_f32());
+    let r = u1.sqrt();
+    let phi = 2. * std::f32::consts::PI * u2;
+    let (t, b) = orthonormal_basis(normal);
+    (t * (r * phi.cos()) + b * (r * phi.sin()) + *normal * (1. - u1).sqrt()).normalized()
+}
+
+// Uniformly random point in the unit ball, by rejection from the enclosing cube
+fn random_in_ball(rng: &mut Rng) -> Vec3 {
+    loop {
+        let p = Vec3::new(2. * rng.next_f32() - 1., 2. * rng.next_f32() - 1., 2. * rng.next_f32() - 1.);
+        if p * p <= 1. { return p; }
+    }
+}
+
+// Random unit direction within angle acos(cos_max) of unit axis, uniform over that cap of the sphere
+fn random_cone_direction(axis: &Vec3, cos_max: f32, rng: &mut Rng) -> Vec3 {
+    let cos_theta = 1. - rng.next_f32() * (1. - cos_max);
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+    let phi = 2. * std::f32::consts::PI * rng.next_f32();
+    let (t, b) = orthonormal_basis(axis);
+    (t * (sin_theta * phi.cos()) + b * (sin_theta * phi.sin()) + *axis * cos_theta).normalized()
+}
+
+// Strategy for placing sub-pixel sample offsets
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum Sampler {
+    Random,
+    // Jittered grid: the pixel is split into n x n cells for the largest n with n^2 <= samples and each takes one
+    // random point, so samples can't clump. Samples beyond n^2 fall back to Random.
+    Stratified,
+    Halton, // Low-discrepancy sequence in bases 2 and 3
+    // Fixed 4-sample rotated grid (RGSS): every sample has its own row and column, so near-horizontal and
+    // near-vertical edges get 4 coverage levels instead of the 2 of an axis-aligned 2x2 grid. Made for
+    // samples = 4; larger counts repeat the pattern and gain nothing.
+    RotatedGrid,
+}
+
+impl Sampler {
+    // Stratified when samples is a perfect square, since then every sample gets a cell; Halton otherwise
+    pub fn default_for(samples: u32) -> Self {
+        let n = (samples as f64).sqrt() as u32;
+        if n * n == samples { Sampler::Stratified } else { Sampler::Halton }
+    }
+
+    // Offset within the unit pixel square for sample number `sample` of `samples` for pixel number `pixel`
+    pub fn offset(&self, pixel: usize, sample: u32, samples: u32, rng: &mut Rng) -> (f32, f32) {
+        match self {
+            Sampler::Random => (rng.next_f32(), rng.next_f32()),
+            Sampler::Stratified => {
+                let n = (samples as f64).sqrt() as u32;
+                if sample >= n * n { return (rng.next_f32(), rng.next_f32()); }
+                let (cx, cy) = ((sample % n) as f32, (sample / n) as f32);
+                ((cx + rng.next_f32()) / n as f32, (cy + rng.next_f32()) / n as f32)
+            }
+            Sampler::Halton => {
+                // Each pixel starts at a hashed index so neighbours don't share the same pattern
+                let i = (mix(pixel as u64) >> 32) + sample as u64;
+                (radical_inverse(i, 2), radical_inverse(i, 3))
+            }
+            Sampler::RotatedGrid => {
+                const PATTERN: [(f32, f32); 4] = [(0.375, 0.125), (0.875, 0.375), (0.625, 0.875), (0.125, 0.625)];
+                PATTERN[sample as usize % PATTERN.len()]
+            }
+        }
+    }
+}
+
+// What each primary ray records
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum RenderMode {
+    Shaded,
+    PathTraced, // Shaded plus Monte Carlo indirect diffuse light; needs many samples per pixel to converge
+    Normals, // Debug view: surface normal mapped to RGB as (n + 1) / 2, misses are black
+    Depth { near: f32, far: f32 }, // Grayscale hit distance, black at near and white at far or beyond, misses are white
+    ObjectId, // Debug view: a flat arbitrary color per sphere index, to spot overlapping or misplaced geometry; misses are black
+    // Preview of shape and contact shadows with no lights or materials: grayscale fraction of `samples` cosine-weighted
+    // rays from each hit that travel `distance` without hitting anything, 1 fully open and 0 fully enclosed.
+    // Misses are white.
+    AmbientOcclusion { samples: u32, distance: f32 },
+}
+
+// Curve compressing linear HDR color into [0, 1] before encoding
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum ToneMap {
+    Clip, // Identity up to 1, then clamped; exact for in-range scenes but highlights burn out to flat white
+    Reinhard, // c / (1 + c) per channel; never clips, at the cost of dimming and flattening the whole range
+    Aces, // Narkowicz's fit of the ACES filmic curve per channel; punchier midtones with a soft highlight roll-off
+}
+
+impl ToneMap {
+    pub fn apply(&self, c: f32) -> f32 {
+        match self {
+            ToneMap::Clip => c.min(1.),
+            ToneMap::Reinhard => c / (1. + c),
+            ToneMap::Aces => (c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14),
+        }
+    }
+}
+
+// Transfer function applied to clamped linear output before quantizing to 8 bits
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum Encoding {
+    Linear, // No curve, values written as-is
+    Srgb, // Piecewise sRGB curve
+    Gamma(f32), // Plain power curve c^(1/gamma), e.g. 2.2 as an approximation of sRGB
+}
+
+impl Encoding {
+    pub fn encode(&self, c: f32) -> f32 {
+        match self {
+            Encoding::Linear => c,
+            Encoding::Srgb => if c <= 0.003_130_8 { 12.92 * c } else { 1.055 * c.powf(1. / 2.4) - 0.055 },
+            Encoding::Gamma(gamma) => c.powf(1. / gamma),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RenderSettings {
+    pub mode: RenderMode,
+    pub width: usize,
+    pub height: usize,
+    pub max_depth: u32, // Secondary ray generations (reflection or diffuse) before rays return the background
+    pub gi_bounces: u32, // Diffuse bounces per path when path tracing; Russian roulette usually ends paths sooner
+    pub samples: u32, // Samples per pixel; 1 shoots a single ray through the pixel center
+    pub sampler: Sampler,
+    pub seed: u64, // With a pixel's position, picks its random stream; the image doesn't depend on threads or tiles
+    // Scale down any single sample brighter than this luminance before averaging, to suppress fireflies.
+    // Trades a little energy (bright highlights come out dimmer) for less noise; only applies when samples > 1.
+    pub max_sample_luminance: Option<f32>,
+    // Shadow rays per shading point toward each light with a radius, averaged into soft shadows; 0 counts as 1.
+    // Penumbra noise falls as this goes up, and so does speed, roughly in proportion.
+    #[serde(default)]
+    pub shadow_samples: u32,
+    pub blur_radius: usize, // Box blur to smooth sampling noise, in pixels; softens detail too, so 0 (off) by default
+    pub tone_map: ToneMap,
+    pub encoding: Encoding,
+    pub exposure: f32, // Photographic stops applied to the final image, color * 2^exposure; 0 leaves it unchanged
+    pub vignette: Option<Vignette>,
+    pub threads: usize, // Worker threads; 0 uses available_parallelism() and 1 renders serially on the calling thread
+    pub tile_size: usize, // Edge length in pixels of the square tiles handed to worker threads
+    #[serde(default)]
+    pub tile_order: TileOrder,
+    pub edge_aa: Option<EdgeAa>,
+    pub crop: Option<(usize, usize, usize, usize)>, // Only render pixels with x0 <= x < x1 and y0 <= y < y1, the rest stay black
+    // Camera rays that miss come back black instead of the background, for overlaying just the objects through the
+    // alpha channel. Reflected and refracted rays still see the background, so the objects look the same as without it.
+    pub transparent_background: bool,
+    // Near clip: every ray ignores hits closer than this to its origin. It's the shadow bias too: rays leaving a
+    // surface (reflections, shadow rays, refraction and bounces) start this far off it before the clip applies,
+    // since on big spheres like the ground rounding error alone outruns a clip of 0.001 and the ray hits the surface
+    // it left. Too small brings back shadow acne, speckles on lit surfaces; too large lets shadows come loose from
+    // contact points and clips away geometry right in front of the camera.
+    #[serde(default = "RenderSettings::default_t_min")]
+    pub t_min: f32,
+    // Draw distance: camera, reflection and bounce rays see the background past this, even if something is there.
+    // Raise it for scenes spread wider than the default 1000 units. Shadow rays check only up to their light.
+    #[serde(default = "RenderSettings::default_max_distance")]
+    pub max_distance: f32,
+}
+
+// Order worker threads take tiles in. Each pixel is seeded from its position, so the image is the same either way.
+// Serial renders always go row by row.
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
+pub enum TileOrder {
+    #[default]
+    Raster, // Left to right, then top to bottom
+    // Z-order curve over the tile grid, so tiles rendered around the same time are close together on screen and
+    // tend to reuse the same geometry while it's still in cache
+    Morton,
+}
+
+// Refinement pass after the main render that re-renders only pixels on visible edges with more samples, as a
+// cheaper alternative to supersampling the whole image. Flat interiors keep their first-pass color.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EdgeAa {
+    // Flags both pixels of a horizontal or vertical neighbour pair whose colors, clamped to [0, 1],
+    // differ by more than this in any channel
+    pub threshold: f32,
+    pub samples: u32, // Samples per flagged pixel, replacing its first-pass color
+}
+
+impl RenderSettings {
+    // Default near clip, large enough to clear rounding at scene scales in the tens of units
+    pub fn default_t_min() -> f32 {
+        0.001
+    }
+
+    pub fn default_max_distance() -> f32 {
+        1000.
+    }
+
+    pub fn in_crop(&self, i: usize, j: usize) -> bool {
+        match self.crop {
+            Some((x0, y0, x1, y1)) => i >= x0 && i < x1 && j >= y0 && j < y1,
+            None => true,
+        }
+    }
+}
+
+// How image-plane positions map to ray directions
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
+pub enum Projection {
+    #[default]
+    Perspective, // Pinhole through a flat image plane, keeping straight lines straight; uses Camera::fov
+    // Equidistant fisheye: the angle off the view axis grows in proportion to distance from the image center, reaching
+    // fov / 2 (radians) on the circle touching the top and bottom edges. Pixels outside that circle stay black.
+    Fisheye { fov: f32 },
+    // Full 360° panorama: x maps to longitude and y to latitude with the same layout as Background::Environment,
+    // -z at the center column and straight up along the top row. Needs a 2:1 image to keep the sphere undistorted;
+    // fov, aspect_ratio and distortion don't apply.
+    Equirect,
+}
+
+// Camera looking down its local -z axis, with +y up
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Camera {
+    pub position: Vec3,
+    pub orientation: Mat3, // Rotates camera-space directions into world space
+    pub fov: f32, // Vertical field of view in radians, for the perspective projection
+    #[serde(default)]
+    pub projection: Projection,
+    pub aspect_ratio: Option<f32>, // Image-plane width / height; None uses width / height of the image
+    // Radial lens distortion k: image-plane points at distance r from the center (1 at the top and bottom edges) are
+    // pushed out to r (1 + k r^2). Positive k gives barrel distortion, negative pincushion, and 0 a pinhole camera.
+    #[serde(default)]
+    pub distortion: f32,
+}
+
+impl Camera {
+    // At the origin looking down -z
+    pub fn new(fov: f32) -> Self {
+        Camera { position: Vec3::origin(), orientation: Mat3::identity(), fov, projection: Projection::Perspective, aspect_ratio: None, distortion: 0. }
+    }
+
+    // Ray through continuous image coordinates (px, py) of a width x height image, with (0, 0) the top-left corner.
+    // None where the projection doesn't cover the image, outside a fisheye's circle.
+    pub fn primary_ray(&self, width: usize, height: usize, px: f32, py: f32) -> Option<Ray> {
+        let (width, height) = (width as f32, height as f32);
+        let aspect_ratio = self.aspect_ratio.unwrap_or(width / height);
+        let (u, v) = ((2. * px / width - 1.) * aspect_ratio, -(2. * py / height - 1.));
+        let distortion = 1. + self.distortion * (u * u + v * v);
+        let dir = match self.projection {
+            Projection::Perspective => {
+                let x = (self.fov / 2.).tan() * (2. * px / width - 1.) * aspect_ratio * distortion;
+                let y = (self.fov / 2.).tan() * -(2. * py / height - 1.) * distortion;
+                Vec3::new(x, y, -1.).normalized()
+            }
+            Projection::Fisheye { fov } => {
+                let (u, v) = (u * distortion, v * distortion);
+                let r = (u * u + v * v).sqrt();
+                if r > 1. { return None; }
+                if r == 0. { Vec3::new(0., 0., -1.) } else {
+                    let (sin, cos) = (r * fov / 2.).sin_cos();
+                    Vec3::new(u / r * sin, v / r * sin, -cos)
+                }
+            }
+            Projection::Equirect => {
+                let longitude = (px / width - 0.5) * 2. * std::f32::consts::PI;
+                let polar = py / height * std::f32::consts::PI; // Angle down from straight up
+                let (sin_lon, cos_lon) = longitude.sin_cos();
+                let (sin_polar, cos_polar) = polar.sin_cos();
+                Vec3::new(sin_lon * sin_polar, cos_polar, -cos_lon * sin_polar)
+            }
+        };
+        Some(Ray { origin: self.position, dir: self.orientation * dir })
+    }
+
+    // Angle between the rays through neighbouring pixels at the image center
+    pub fn pixel_angle(&self, height: usize) -> f32 {
+        let height = height as f32;
+        match self.projection {
+            Projection::Perspective => 2. * (self.fov / 2.).tan() / height, // Image plane one unit away
+            Projection::Fisheye { fov } => fov / height,
+            Projection::Equirect => std::f32::consts::PI / height,
+        }
+    }
+
+    // Indices of the spheres at least partly inside the view pyramid (or a fisheye's cone) of a width x height image,
+    // in order. Camera rays can't hit the rest, though reflections and shadows still can.
+    pub fn visible_spheres(&self, spheres: &[Sphere], width: usize, height: usize) -> Vec<usize> {
+        if let Projection::Equirect = self.projection { return (0..spheres.len()).collect(); }
+        if let Projection::Fisheye { fov } = self.projection {
+            // Every ray is within fov / 2 of the view axis, however the lens distorts
+            let forward = self.orientation * Vec3::new(0., 0., -1.);
+            return (0..spheres.len()).filter(|&i| {
+                let offset = spheres[i].center - self.position;
+                let (d, radius) = (offset.mag(), spheres[i].radius * (1. + 1e-4));
+                d <= radius || (offset * forward / d).clamp(-1., 1.).acos() - (radius / d).asin() <= fov / 2.
+            }).collect();
+        }
+
+        // Distortion bends the image edges, so bound it with a pinhole camera wide enough to take in the corners,
+        // which barrel distortion pushes out furthest. Pincushion only pulls rays in.
+        let aspect_ratio = self.aspect_ratio.unwrap_or(width as f32 / height as f32);
+        let reach = (1. + self.distortion * (aspect_ratio * aspect_ratio + 1.)).max(1.);
+        let pinhole = Camera { fov: 2. * ((self.fov / 2.).tan() * reach).atan(), distortion: 0., ..*self };
+        let ray_dir = |px: usize, py: usize| {
+            pinhole.primary_ray(width, height, px as f32, py as f32).expect("perspective projection covers the whole image").dir
+        };
+        let corners = [(0, 0), (width, 0), (width, height), (0, height)].map(|(px, py)| ray_dir(px, py));
+        let forward = ray_dir(width / 2, height / 2);
+        // Inward normals of the four side planes, which all pass through the camera position
+        let planes = [0, 1, 2, 3].map(|k| {
+            let n = corners[k].cross(&corners[(k + 1) % 4]).normalized();
+            if n * forward < 0. { -n } else { n }
+        });
+        (0..spheres.len()).filter(|&i| {
+            let offset = spheres[i].center - self.position;
+            planes.iter().all(|n| offset * *n > -spheres[i].radius * (1. + 1e-4))
+        }).collect()
+    }
+}
+
+// Linear HDR colors from the tracer in row-major order, (0, 0) at the top-left. Values are unclamped;
+// rendering and post-processing read and write these, and only to_ldr reduces them to 8 bits.
+pub struct Framebuffer {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Vec3>,
+    pub alpha: Vec<f32>, // Coverage: fraction of each pixel's camera rays that hit geometry, so edges come out fractional
+    pub rays: RayStats, // Rays cast rendering this image, edge refinement included
+}
+
+impl Framebuffer {
+    // All black and transparent
+    pub fn new(width: usize, height: usize) -> Self {
+        Framebuffer { width, height, pixels: vec![Vec3::origin(); width * height], alpha: vec![0.; width * height], rays: RayStats::default() }
+    }
+
+    pub fn get(&self, i: usize, j: usize) -> Vec3 {
+        self.pixels[j * self.width + i]
+    }
+
+    // 8-bit RGB image for display or saving, in the same order, through the to_display output pipeline
+    pub fn to_ldr(&self, settings: &RenderSettings) -> Vec<[u8; 3]> {
+        self.pixels.iter().enumerate().map(|(k, &color)| {
+            let d = to_display(color, k % self.width, k / self.width, settings);
+            [(d.x * 255.) as u8, (d.y * 255.) as u8, (d.z * 255.) as u8]
+        }).collect()
+    }
+
+    // to_ldr with coverage as a fourth, straight (not premultiplied) alpha channel, e.g. for write_png.
+    // With a transparent background, edge colors are blended with black, so they are divided by coverage first.
+    pub fn to_ldr_rgba(&self, settings: &RenderSettings) -> Vec<[u8; 4]> {
+        self.pixels.iter().zip(&self.alpha).enumerate().map(|(k, (&color, &a))| {
+            let a = a.clamp(0., 1.);
+            let color = if settings.transparent_background && a > 0. { color * (1. / a) } else { color };
+            let d = to_display(color, k % self.width, k / self.width, settings);
+            [(d.x * 255.) as u8, (d.y * 255.) as u8, (d.z * 255.) as u8, (a * 255. + 0.5) as u8]
+        }).collect()
+    }
+}
+
+// Writes an 8-bit RGBA PNG of rows of pixels, (0, 0) at the top-left. Compressed with stored (uncompressed) deflate
+// blocks, so files are about as large as the raw pixels but need no compression library.
+pub fn write_png<W: io::Write>(out: &mut W, width: usize, height: usize, pixels: &[[u8; 4]]) -> io::Result<()> {
+    fn crc32(bytes: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for &b in bytes {
+            crc ^= b as u32;
+            for _ in 0..8 { crc = if crc & 1 != 0 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 }; }
+        }
+        !crc
+    }
+    fn chunk<W: io::Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+        out.write_all(&(data.len() as u32).to_be_bytes())?;
+        let body = [&kind[..], data].concat();
+        out.write_all(&body)?;
+        out.write_all(&crc32(&body).to_be_bytes())
+    }
+
+    // Each row starts with filter type 0 (none)
+    let mut raw = Vec::with_capacity(height * (1 + 4 * width));
+    for row in pixels.chunks(width) {
+        raw.push(0);
+        raw.extend(row.iter().flatten());
+    }
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xffff).peekable();
+    if blocks.peek().is_none() { zlib.extend([1, 0, 0, 0xff, 0xff]); }
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+        zlib.push(blocks.peek().is_none() as u8); // BFINAL on the last block, BTYPE 00 (stored)
+        zlib.extend(len.to_le_bytes());
+        zlib.extend((!len).to_le_bytes());
+        zlib.extend(block);
+    }
+    let (a, b) = raw.iter().fold((1u32, 0u32), |(a, b), &x| ((a + x as u32) % 65521, (b + a + x as u32) % 65521));
+    zlib.extend(((b << 16) | a).to_be_bytes()); // Adler-32
+
+    let mut header = Vec::with_capacity(13);
+    header.extend((width as u32).to_be_bytes());
+    header.extend((height as u32).to_be_bytes());
+    header.extend([8, 6, 0, 0, 0]); // 8 bits per channel, RGBA, deflate, adaptive filtering, no interlace
+    out.write_all(b"\x89PNG\r\n\x1a\n")?;
+    chunk(out, b"IHDR", &header)?;
+    chunk(out, b"IDAT", &zlib)?;
+    chunk(out, b"IEND", &[])
+}
+
+// Camera for a render, with the spheres its rays can possibly hit when worked out ahead of time
+struct View<'a> {
+    camera: &'a Camera,
+    visible: Option<Vec<usize>>, // None tests every sphere, through the grid if there is one
+    // Angle between neighbouring samples' rays, taken as how fast each ray's beam widens for texture filtering.
+    // Reflections and refractions keep it, though curved surfaces really focus or spread their beams.
+    spread: f32,
+}
+
+// Color seen along a primary ray according to settings.mode, and whether the ray hit geometry. The ray is tested
+// against the spheres in `visible` when given, as with View::visible.
+fn trace(ray: &Ray, scene: &Scene, view: &View, visible: Option<&[usize]>, settings: &RenderSettings, rng: &mut Rng,
+         rays: &mut RayStats) -> (Vec3, bool) {
+    rays.primary += 1;
+    let shaded = |global_illumination: bool, rng: &mut Rng, rays: &mut RayStats| {
+        let mut path = PathState::new(settings, rng, rays, global_illumination);
+        path.visible = visible;
+        path.spread = view.spread;
+        let color = raycast(ray, scene, 0, &mut path);
+        if settings.transparent_background && !path.covered { return (Vec3::origin(), false); }
+        (color, path.covered)
+    };
+    let primary_hit = || primary_hit(ray, scene, visible, settings.t_min, settings.max_distance, view.spread);
+    match settings.mode {
+        RenderMode::Shaded => shaded(false, rng, rays),
+        RenderMode::PathTraced => shaded(true, rng, rays),
+        RenderMode::Normals => match primary_hit() {
+            Some(hit) => ((hit.normal + Vec3::new(1., 1., 1.)) * 0.5, true),
+            None => (Vec3::origin(), false),
+        },
+        RenderMode::Depth { near, far } => {
+            let hit = primary_hit();
+            let depth = match &hit {
+                Some(hit) => ((hit.distance - near) / (far - near)).clamp(0., 1.),
+                None => 1.,
+            };
+            (Vec3::new(depth, depth, depth), hit.is_some())
+        }
+        RenderMode::ObjectId => match primary_hit() {
+            Some(hit) => {
+                let h = mix(hit.index as u64);
+                let channel = |shift: u32| 0.2 + 0.8 * ((h >> shift) & 0xff) as f32 / 255.; // Kept off black so hits stand out from misses
+                (Vec3::new(channel(0), channel(8), channel(16)), true)
+            }
+            None => (Vec3::origin(), false),
+        },
+        RenderMode::AmbientOcclusion { samples, distance } => match primary_hit() {
+            Some(hit) => {
+                let normal = if ray.dir * hit.normal > 0. { -hit.normal } else { hit.normal };
+                let samples = samples.max(1);
+                let open = (0..samples).filter(|_| {
+                    rays.shadow += 1;
+                    let dir = random_cosine_direction(&normal, rng);
+                    let origin = hit.point + normal * settings.t_min;
+                    scene_hit(&Ray { origin, dir }, scene, settings.t_min, distance).is_none()
+                }).count();
+                let open = open as f32 / samples as f32;
+                (Vec3::new(open, open, open), true)
+            }
+            None => (Vec3::new(1., 1., 1.), false),
+        },
+    }
+}
+
+// Camera ray for one sample of pixel (i, j): through the pixel center when there's a single sample per pixel,
+// otherwise offset by settings.sampler. None where the projection has no ray, as outside a fisheye's circle.
+fn sample_ray(view: &View, settings: &RenderSettings, i: usize, j: usize, sample: u32, rng: &mut Rng) -> Option<Ray> {
+    let (dx, dy) = if settings.samples <= 1 {
+        (0.5, 0.5)
+    } else {
+        settings.sampler.offset(j * settings.width + i, sample, settings.samples, rng)
+    };
+    view.camera.primary_ray(settings.width, settings.height, i as f32 + dx, j as f32 + dy)
+}
+
+// Running total of one pixel's samples, as traced (color, hit) or None for a sample with no camera ray
+#[derive(Debug, Copy, Clone)]
+struct PixelSum {
+    color: Vec3,
+    hits: u32,
+}
+
+impl PixelSum {
+    fn new() -> Self {
+        PixelSum { color: Vec3::origin(), hits: 0 }
+    }
+
+    // Samples brighter than settings.max_sample_luminance are scaled down to it first, unless there's only the one
+    fn add(&mut self, sample: Option<(Vec3, bool)>, settings: &RenderSettings) {
+        let (mut color, hit) = sample.unwrap_or((Vec3::origin(), false));
+        if let (true, Some(max)) = (settings.samples > 1, settings.max_sample_luminance) {
+            let lum = color.luminance();
+            if lum > max { color = color * (max / lum); }
+        }
+        self.color = self.color + color;
+        self.hits += hit as u32;
+    }
+
+    // The mean color and the fraction of samples that hit geometry
+    fn average(&self, settings: &RenderSettings) -> (Vec3, f32) {
+        let n = settings.samples.max(1) as f32;
+        (self.color * (1. / n), self.hits as f32 / n)
+    }
+}
+
+// Averages settings.samples rays through pixel (i, j), returning the color and the fraction of rays that hit geometry
+fn render_pixel(scene: &Scene, view: &View, settings: &RenderSettings, i: usize, j: usize, rng: &mut Rng,
+                rays: &mut RayStats) -> (Vec3, f32) {
+    let mut sum = PixelSum::new();
+    for sample in 0..settings.samples.max(1) {
+        let ray = sample_ray(view, settings, i, j, sample, rng);
+        sum.add(ray.map(|ray| trace(&ray, scene, view, view.visible.as_deref(), settings, rng, rays)), settings);
+    }
+    sum.average(settings)
+}
+
+// Rectangle of pixels rendered as one unit of work
+#[derive(Debug, Copy, Clone)]
+struct Tile {
+    x: usize,
+    y: usize,
+    w: usize,
+    h: usize,
+}
+
+// Splits the image into settings.tile_size squares in raster order, smaller at the right and bottom edges
+fn tiles(settings: &RenderSettings) -> Vec<Tile> {
+    let size = settings.tile_size.max(1);
+    let mut tiles = Vec::new();
+    for y in (0..settings.height).step_by(size) {
+        for x in (0..settings.width).step_by(size) {
+            tiles.push(Tile { x, y, w: size.min(settings.width - x), h: size.min(settings.height - y) });
+        }
+    }
+    tiles
+}
+
+// Interleaves the bits of x and y, x in the even bits, giving each point's position along the Z-order curve
+fn morton(x: u32, y: u32) -> u64 {
+    let spread = |v: u32| {
+        let mut v = v as u64;
+        v = (v | (v << 16)) & 0x0000_ffff_0000_ffff;
+        v = (v | (v << 8)) & 0x00ff_00ff_00ff_00ff;
+        v = (v | (v << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+        v = (v | (v << 2)) & 0x3333_3333_3333_3333;
+        (v | (v << 1)) & 0x5555_5555_5555_5555
+    };
+    spread(x) | (spread(y) << 1)
+}
+
+// Renders the pixels of one tile in row-major order, as (color, coverage)
+fn render_tile(scene: &Scene, view: &View, settings: &RenderSettings, tile: &Tile, rays: &mut RayStats) -> Vec<(Vec3, f32)> {
+    #[cfg(feature = "packets")]
+    if let Some(visible) = &view.visible {
+        return render_tile_packets(scene, view, visible, settings, tile, rays);
+    }
+    let mut pixels = Vec::with_capacity(tile.w * tile.h);
+    for j in tile.y..tile.y + tile.h {
+        for i in tile.x..tile.x + tile.w {
+            pixels.push(if settings.in_crop(i, j) {
+                render_pixel(scene, view, settings, i, j, &mut Rng::for_pixel(settings.seed, i, j), rays)
+            } else {
+                (Vec3::origin(), 0.)
+            });
+        }
+    }
+    pixels
+}
+
+// Four camera rays intersected with the spheres together, one per lane, stored component by component so each step
+// of the sphere test runs across all four lanes at once. Lanes without a ray never hit anything.
+#[cfg(feature = "packets")]
+struct RayPacket {
+    origin: [[f32; 4]; 3],
+    dir: [[f32; 4]; 3], // Normalized, as Sphere::ray_roots takes it
+    active: [bool; 4],
+}
+
+#[cfg(feature = "packets")]
+impl RayPacket {
+    fn new(rays: &[Option<Ray>; 4]) -> Self {
+        let mut packet = RayPacket { origin: [[0.; 4]; 3], dir: [[0.; 4]; 3], active: [false; 4] };
+        for (lane, ray) in rays.iter().enumerate() {
+            if let Some(ray) = ray {
+                let dir = ray.dir.normalized();
+                for axis in 0..3 {
+                    packet.origin[axis][lane] = ray.origin[axis];
+                    packet.dir[axis][lane] = dir[axis];
+                }
+                packet.active[lane] = true;
+            }
+        }
+        packet
+    }
+
+    // nearest_sphere for each lane over the candidates, to the bit: every lane repeats Sphere::ray_roots and
+    // intersects_ray operation for operation, and ties go to the lowest index the same way
+    fn nearest_sphere(&self, spheres: &[Sphere], candidates: &[usize], t_min: f32) -> [Option<(usize, f32)>; 4] {
+        let [ox, oy, oz] = &self.origin;
+        let [dx, dy, dz] = &self.dir;
+        let mut nearest = [None; 4];
+        for &i in candidates {
+            let sphere = &spheres[i];
+            let radius_sq = sphere.radius.powi(2);
+            let (mut discriminant, mut t_0, mut t_1) = ([0.; 4], [0.; 4], [0.; 4]);
+            for lane in 0..4 {
+                let oc = [ox[lane] - sphere.center.x, oy[lane] - sphere.center.y, oz[lane] - sphere.center.z];
+                let b = oc[0] * dx[lane] + oc[1] * dy[lane] + oc[2] * dz[lane];
+                let c = oc[0] * oc[0] + oc[1] * oc[1] + oc[2] * oc[2] - radius_sq;
+                discriminant[lane] = b * b - c;
+                let q = -b - discriminant[lane].sqrt().copysign(b);
+                let (t_a, t_b) = (c / q, q);
+                t_0[lane] = t_a.min(t_b);
+                t_1[lane] = t_a.max(t_b);
+            }
+            for lane in 0..4 {
+                if !self.active[lane] || discriminant[lane] <= radius_sq * GRAZE_TOLERANCE { continue; }
+                let cur_dist = if t_0[lane] > t_min { t_0[lane] } else if t_1[lane] > t_min { t_1[lane] } else { continue };
+                let closer = match nearest[lane] {
+                    Some((min_i, min_dist)) => cur_dist < min_dist || (cur_dist == min_dist && i < min_i),
+                    None => true,
+                };
+                if closer { nearest[lane] = Some((i, cur_dist)); }
+            }
+        }
+        nearest
+    }
+}
+
+// render_tile a 2x2 block of pixels at a time, for brute-force intersection over the spheres in `visible`. Each
+// sample's four camera rays are intersected as a RayPacket, then shaded one by one with the sphere already found,
+// and each pixel keeps its own random stream, so the image is the same as from render_tile.
+#[cfg(feature = "packets")]
+fn render_tile_packets(scene: &Scene, view: &View, visible: &[usize], settings: &RenderSettings, tile: &Tile,
+                       rays: &mut RayStats) -> Vec<(Vec3, f32)> {
+    let mut pixels = vec![(Vec3::origin(), 0.); tile.w * tile.h];
+    for y in (tile.y..tile.y + tile.h).step_by(2) {
+        for x in (tile.x..tile.x + tile.w).step_by(2) {
+            let block = [(x, y), (x + 1, y), (x, y + 1), (x + 1, y + 1)].map(|(i, j)| {
+                let inside = i < tile.x + tile.w && j < tile.y + tile.h && settings.in_crop(i, j);
+                if inside { Some((i, j)) } else { None }
+            });
+            let mut rngs = block.map(|pixel| pixel.map(|(i, j)| Rng::for_pixel(settings.seed, i, j)));
+            let mut sums = [PixelSum::new(); 4];
+            for sample in 0..settings.samples.max(1) {
+                let mut packet = [None, None, None, None];
+                for lane in 0..4 {
+                    if let (Some((i, j)), Some(rng)) = (block[lane], &mut rngs[lane]) {
+                        packet[lane] = sample_ray(view, settings, i, j, sample, rng);
+                    }
+                }
+                let nearest = RayPacket::new(&packet).nearest_sphere(&scene.spheres, visible, settings.t_min);
+                for lane in 0..4 {
+                    if let Some(rng) = &mut rngs[lane] {
+                        // Handing trace just the sphere that was hit, or none, gets it the same hit for one more test
+                        let hit = nearest[lane].map(|(i, _)| i);
+                        let candidates = hit.as_slice();
+                        let traced = packet[lane].as_ref()
+                            .map(|ray| trace(ray, scene, view, Some(candidates), settings, rng, rays));
+                        sums[lane].add(traced, settings);
+                    }
+                }
+            }
+            for (pixel, sum) in block.iter().zip(&sums) {
+                if let Some((i, j)) = pixel {
+                    pixels[(j - tile.y) * tile.w + (i - tile.x)] = sum.average(settings);
+                }
+            }
+        }
+    }
+    pixels
+}
+
+// Sent as each tile finishes, so a preview can repaint just that region
+#[derive(Debug, Copy, Clone)]
+pub struct TileDone {
+    pub x: usize,
+    pub y: usize,
+    pub w: usize,
+    pub h: usize,
+}
+
+// Optional hooks for watching a render as it runs. Both are called or sent from the thread that called render(),
+// so on_pixel needn't be Send or Sync.
+#[derive(Default)]
+pub struct Progress<'a> {
+    // Called as each pixel finishes: in raster order when serial, a tile at a time in completion order when threaded
+    pub on_pixel: Option<&'a mut dyn FnMut(usize, usize, Vec3)>,
+    // Receives a TileDone per finished tile (per row when serial). Rendering carries on if the receiver is dropped.
+    pub tiles: Option<mpsc::Sender<TileDone>>,
+}
+
+impl Progress<'_> {
+    fn pixel_done(&mut self, i: usize, j: usize, color: Vec3) {
+        if let Some(f) = self.on_pixel.as_mut() { f(i, j, color); }
+    }
+
+    fn tile_done(&self, tile: &Tile) {
+        if let Some(sender) = &self.tiles {
+            let _ = sender.send(TileDone { x: tile.x, y: tile.y, w: tile.w, h: tile.h });
+        }
+    }
+}
+
+// Traces every pixel of the image seen by camera
+pub fn render(scene: &Scene, camera: &Camera, settings: &RenderSettings) -> Framebuffer {
+    render_with_progress(scene, camera, settings, Progress::default())
+}
+
+pub fn render_with_progress(scene: &Scene, camera: &Camera, settings: &RenderSettings, mut progress: Progress) -> Framebuffer {
+    let threads = match settings.threads {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    // The grid already only visits cells along each ray, so culling only pays off for brute-force intersection
+    let visible = match scene.grid {
+        Some(_) => None,
+        None => Some(camera.visible_spheres(&scene.spheres, settings.width, settings.height)),
+    };
+    let spread = camera.pixel_angle(settings.height) / (settings.samples.max(1) as f32).sqrt();
+    let view = View { camera, visible, spread };
+    let mut framebuffer = if threads == 1 {
+        render_serial(scene, &view, settings, &mut progress)
+    } else {
+        render_threaded(scene, &view, settings, threads, &mut progress)
+    };
+    if let Some(edge_aa) = settings.edge_aa {
+        refine_edges(&mut framebuffer, scene, &view, settings, &edge_aa, threads, &mut progress);
+    }
+    framebuffer
+}
+
+// Renders the scene once per eye, [left, right], from cameras ipd apart along camera's right vector and centered on
+// it. The eyes look in parallel, so objects at infinity line up and nearer ones shift toward the nose.
+pub fn render_stereo(scene: &Scene, camera: &Camera, settings: &RenderSettings, ipd: f32) -> [Framebuffer; 2] {
+    let right = camera.orientation.col(0);
+    let eye = |side: f32| Camera { position: camera.position + right * (side * ipd / 2.), ..*camera };
+    [render(scene, &eye(-1.), settings), render(scene, &eye(1.), settings)]
+}
+
+// Joins images of the same width and height into one, left to right in the given order; for stereo pairs that's
+// the left eye in the left half and the right eye in the right half. Pixels are row-major, as from to_ldr.
+pub fn side_by_side<T: Copy>(images: &[Vec<T>], width: usize) -> Vec<T> {
+    let height = images.first().map_or(0, |image| image.len() / width.max(1));
+    let mut out = Vec::with_capacity(images.len() * width * height);
+    for j in 0..height {
+        for image in images {
+            out.extend_from_slice(&image[j * width..(j + 1) * width]);
+        }
+    }
+    out
+}
+
+// Pixels inside the crop whose color stands out from a horizontal or vertical neighbour's, in row-major order
+fn edge_pixels(framebuffer: &Framebuffer, settings: &RenderSettings, threshold: f32) -> Vec<usize> {
+    let (width, height) = (framebuffer.width, framebuffer.height);
+    let differs = |a: usize, b: usize| {
+        let (ca, cb) = (framebuffer.pixels[a], framebuffer.pixels[b]);
+        (0..3).any(|c| (ca[c].clamp(0., 1.) - cb[c].clamp(0., 1.)).abs() > threshold)
+    };
+    let mut flagged = vec![false; width * height];
+    for j in 0..height {
+        for i in 0..width {
+            let k = j * width + i;
+            if i + 1 < width && differs(k, k + 1) { flagged[k] = true; flagged[k + 1] = true; }
+            if j + 1 < height && differs(k, k + width) { flagged[k] = true; flagged[k + width] = true; }
+        }
+    }
+    (0..width * height).filter(|&k| flagged[k] && settings.in_crop(k % width, k / width)).collect()
+}
+
+// Re-renders edge pixels with edge_aa.samples each, on a stream of their own rather than replaying the first pass's.
+// on_pixel sees refined pixels a second time.
+fn refine_edges(framebuffer: &mut Framebuffer, scene: &Scene, view: &View, settings: &RenderSettings, edge_aa: &EdgeAa,
+                threads: usize, progress: &mut Progress) {
+    let pixels = edge_pixels(framebuffer, settings, edge_aa.threshold);
+    if pixels.is_empty() { return; }
+    let refine_settings = RenderSettings { samples: edge_aa.samples, ..settings.clone() };
+    let width = framebuffer.width;
+    let chunk = pixels.len().div_ceil(threads);
+
+    let mut colors = Vec::with_capacity(pixels.len());
+    thread::scope(|s| {
+        let workers: Vec<_> = pixels.chunks(chunk).map(|chunk| {
+            let refine_settings = &refine_settings;
+            s.spawn(move || {
+                let mut rays = RayStats::default();
+                let colors: Vec<_> = chunk.iter().map(|&k| {
+                    let mut rng = Rng::for_pixel(!settings.seed, k % width, k / width);
+                    render_pixel(scene, view, refine_settings, k % width, k / width, &mut rng, &mut rays)
+                }).collect();
+                (colors, rays)
+            })
+        }).collect();
+        for worker in workers {
+            let (chunk_colors, rays) = worker.join().unwrap();
+            colors.extend(chunk_colors);
+            framebuffer.rays += rays;
+        }
+    });
+
+    for (&k, (color, alpha)) in pixels.iter().zip(colors) {
+        framebuffer.pixels[k] = color;
+        framebuffer.alpha[k] = alpha;
+        progress.pixel_done(k % width, k / width, color);
+    }
+}
+
+// Renders strips the width of the image, a row tall, or two rows when 2x2 packets need them
+fn render_serial(scene: &Scene, view: &View, settings: &RenderSettings, progress: &mut Progress) -> Framebuffer {
+    let mut framebuffer = Framebuffer::new(settings.width, settings.height);
+    let strip_rows = if cfg!(feature = "packets") { 2 } else { 1 };
+    for y in (0..settings.height).step_by(strip_rows) {
+        let strip = Tile { x: 0, y, w: settings.width, h: strip_rows.min(settings.height - y) };
+        let pixels = render_tile(scene, view, settings, &strip, &mut framebuffer.rays);
+        for j in y..y + strip.h {
+            for i in 0..settings.width {
+                let (color, alpha) = pixels[(j - y) * settings.width + i];
+                progress.pixel_done(i, j, color);
+                framebuffer.pixels[j * settings.width + i] = color;
+                framebuffer.alpha[j * settings.width + i] = alpha;
+            }
+            progress.tile_done(&Tile { x: 0, y: j, w: settings.width, h: 1 });
+        }
+    }
+    framebuffer
+}
+
+// Worker threads take the next tile from a shared counter and send finished tiles back over a channel,
+// where the calling thread copies them into the framebuffer. Each worker's ray counts come back when it's joined.
+fn render_threaded(scene: &Scene, view: &View, settings: &RenderSettings, threads: usize, progress: &mut Progress) -> Framebuffer {
+    let tiles = tiles(settings);
+    let mut order: Vec<usize> = (0..tiles.len()).collect();
+    if let TileOrder::Morton = settings.tile_order {
+        let size = settings.tile_size.max(1);
+        order.sort_by_key(|&t| morton((tiles[t].x / size) as u32, (tiles[t].y / size) as u32));
+    }
+    let next_tile = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    let mut framebuffer = Framebuffer::new(settings.width, settings.height);
+
+    thread::scope(|s| {
+        let workers: Vec<_> = (0..threads).map(|_| {
+            let sender = sender.clone();
+            let (tiles, order, next_tile) = (&tiles, &order, &next_tile);
+            s.spawn(move || {
+                let mut rays = RayStats::default();
+                while let Some(&t) = order.get(next_tile.fetch_add(1, Ordering::Relaxed)) {
+                    let pixels = render_tile(scene, view, settings, &tiles[t], &mut rays);
+                    if sender.send((t, pixels)).is_err() { break; }
+                }
+                rays
+            })
+        }).collect();
+        drop(sender); // Receiving ends once every worker has dropped its sender
+
+        for (t, pixels) in receiver {
+            let tile = tiles[t];
+            for (k, (color, alpha)) in pixels.into_iter().enumerate() {
+                let (i, j) = (tile.x + k % tile.w, tile.y + k / tile.w);
+                progress.pixel_done(i, j, color);
+                framebuffer.pixels[j * settings.width + i] = color;
+                framebuffer.alpha[j * settings.width + i] = alpha;
+            }
+            progress.tile_done(&tile);
+        }
+        for worker in workers {
+            framebuffer.rays += worker.join().unwrap();
+        }
+    });
+    framebuffer
+}
+
+// Separable box blur over a row-major width x height image, averaging a (2 * radius + 1)^2 window.
+// Windows are cut off at the image edges rather than padded.
+fn box_blur(data: &[Vec3], width: usize, height: usize, radius: usize) -> Vec<Vec3> {
+    let blur_pass = |src: &[Vec3], horizontal: bool| -> Vec<Vec3> {
+        let mut out = vec![Vec3::origin(); src.len()];
+        for j in 0..height {
+            for i in 0..width {
+                let (pos, len) = if horizontal { (i, width) } else { (j, height) };
+                let (lo, hi) = (pos.saturating_sub(radius), (pos + radius).min(len - 1));
+                let mut sum = Vec3::origin();
+                for k in lo..=hi {
+                    sum = sum + if horizontal { src[j * width + k] } else { src[k * width + i] };
+                }
+                out[j * width + i] = sum * (1. / (hi - lo + 1) as f32);
+            }
+        }
+        out
+    };
+    blur_pass(&blur_pass(data, true), false)
+}
+
+// Image-space filters applied to the linear framebuffer before the output pipeline
+pub fn post_process(mut framebuffer: Framebuffer, settings: &RenderSettings) -> Framebuffer {
+    if settings.blur_radius > 0 {
+        framebuffer.pixels = box_blur(&framebuffer.pixels, framebuffer.width, framebuffer.height, settings.blur_radius);
+    }
+    framebuffer
+}
+
+// Darkens pixels toward the image corners
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Vignette {
+    pub strength: f32, // Fraction of brightness removed at the corners
+    pub radius: f32, // Distance from the center where darkening starts, with 1 at the corners; 1 or more is no vignette
+}
+
+impl Vignette {
+    pub fn factor(&self, i: usize, j: usize, width: usize, height: usize) -> f32 {
+        let (cx, cy) = (width as f32 / 2., height as f32 / 2.);
+        let (dx, dy) = (i as f32 + 0.5 - cx, j as f32 + 0.5 - cy);
+        let d = (dx * dx + dy * dy).sqrt() / (cx * cx + cy * cy).sqrt();
+        if self.radius >= 1. { return 1.; } // No pixel center reaches the corners, so there's nothing to darken
+        let t = ((d - self.radius) / (1. - self.radius)).clamp(0., 1.);
+        1. - self.strength * t * t
+    }
+}
+
+// Output pipeline: maps a linear color from the tracer at pixel (i, j) to the displayable [0, 1] range written
+// to the image. Exposure, tone mapping, vignette, clamp, then encoding.
+pub fn to_display(color: Vec3, i: usize, j: usize, settings: &RenderSettings) -> Vec3 {
+    let exposed = color * settings.exposure.exp2();
+    let vignette = settings.vignette.map_or(1., |v| v.factor(i, j, settings.width, settings.height));
+    let encode = |c: f32| settings.encoding.encode((settings.tone_map.apply(c.max(0.)) * vignette).min(1.));
+    Vec3::new(encode(exposed.x), encode(exposed.y), encode(exposed.z))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::{FRAC_PI_2, PI};
+
+    const X: Vec3 = Vec3 { x: 1., y: 0., z: 0. };
+    const Y: Vec3 = Vec3 { x: 0., y: 1., z: 0. };
+    const Z: Vec3 = Vec3 { x: 0., y: 0., z: 1. };
+
+    #[test]
+    fn mat3_quarter_turn_about_z_maps_x_to_y() {
+        let m = Mat3::from_axis_angle(&Z, FRAC_PI_2);
+        assert!((m * X).approx_eq(&Y, 1e-6));
+        assert!((m * Y).approx_eq(&-X, 1e-6));
+        assert!((m * Z).approx_eq(&Z, 1e-6));
+    }
+
+    #[test]
+    fn mat3_from_euler_composes_x_then_y_then_z() {
+        let (x, y, z) = (0.3, -1.1, 2.);
+        let composed = Mat3::from_axis_angle(&Z, z) * Mat3::from_axis_angle(&Y, y) * Mat3::from_axis_angle(&X, x);
+        let v = Vec3::new(0.2, -0.7, 1.5);
+        assert!((Mat3::from_euler(x, y, z) * v).approx_eq(&(composed * v), 1e-5));
+        // x quarter turn takes y to z, then the y quarter turn takes z to x
+        assert!((Mat3::from_euler(FRAC_PI_2, FRAC_PI_2, 0.) * Y).approx_eq(&X, 1e-6));
+        assert!((Mat3::from_euler(x, y, z).transposed() * (Mat3::from_euler(x, y, z) * v)).approx_eq(&v, 1e-5));
+    }
+
+    #[test]
+    fn quat_two_quarter_turns_make_a_half_turn() {
+        let axis = Vec3::new(1., 2., -0.5);
+        let quarter = Quat::from_axis_angle(&axis, FRAC_PI_2);
+        let half = Quat::from_axis_angle(&axis, PI);
+        let v = Vec3::new(0.4, -1., 2.);
+        assert!((quarter * quarter).rotate(&v).approx_eq(&half.rotate(&v), 1e-5));
+        assert!(quarter.rotate(&quarter.rotate(&v)).approx_eq(&half.rotate(&v), 1e-5));
+    }
+
+    #[test]
+    fn quat_slerp_halfway_is_half_the_angle() {
+        let a = Quat::from_axis_angle(&Z, 0.2);
+        let b = Quat::from_axis_angle(&Z, 1.8);
+        let mid = a.slerp(&b, 0.5);
+        assert!(mid.rotate(&X).approx_eq(&Quat::from_axis_angle(&Z, 1.).rotate(&X), 1e-6));
+        assert!(a.slerp(&b, 0.).rotate(&Y).approx_eq(&a.rotate(&Y), 1e-6));
+    }
+
+    #[test]
+    fn vec3_reads_arrays_and_objects_and_writes_arrays() {
+        let v = Vec3::new(1.5, -2., 0.25);
+        assert_eq!(serde_json::from_str::<Vec3>("[1.5, -2, 0.25]").unwrap(), v);
+        assert_eq!(serde_json::from_str::<Vec3>(r#"{"z": 0.25, "x": 1.5, "y": -2}"#).unwrap(), v);
+        assert_eq!(serde_json::to_string(&v).unwrap(), "[1.5,-2.0,0.25]");
+        assert_eq!(serde_json::from_str::<Vec3>(&serde_json::to_string(&v).unwrap()).unwrap(), v);
+    }
+
+    #[test]
+    fn vec3_rejects_malformed_input() {
+        let cases = [
+            ("[1, 2]", "invalid length 2"),
+            ("[1, 2, 3, 4]", "invalid length 4"),
+            (r#"{"x": 1, "y": 2, "x": 3}"#, "duplicate field `x`"),
+            (r#"{"x": 1, "y": 2, "w": 3}"#, "unknown field `w`"),
+            (r#"{"x": 1, "y": 2}"#, "missing field `z`"),
+        ];
+        for (input, expected) in cases {
+            let err = serde_json::from_str::<Vec3>(input).unwrap_err().to_string();
+            assert!(err.contains(expected), "{}: got \"{}\", expected \"{}\"", input, err, expected);
+        }
+    }
+
+    #[test]
+    fn ray_at_steps_along_dir() {
+        let ray = Ray { origin: Vec3::new(1., 2., 3.), dir: Vec3::new(0., 0., -1.) };
+        assert_eq!(ray.at(0.), ray.origin);
+        assert_eq!(ray.at(2.5), Vec3::new(1., 2., 0.5));
+    }
+
+    // Serial, one sample per pixel and otherwise plain, so renders in tests are quick and repeatable
+    fn test_settings(width: usize, height: usize) -> RenderSettings {
+        RenderSettings {
+            mode: RenderMode::Shaded,
+            width,
+            height,
+            max_depth: 4,
+            gi_bounces: 3,
+            samples: 1,
+            sampler: Sampler::default_for(1),
+            seed: 0,
+            max_sample_luminance: None,
+            shadow_samples: 1,
+            blur_radius: 0,
+            tone_map: ToneMap::Clip,
+            encoding: Encoding::Srgb,
+            exposure: 0.,
+            vignette: None,
+            threads: 1,
+            tile_size: 16,
+            tile_order: TileOrder::Raster,
+            edge_aa: None,
+            crop: None,
+            transparent_background: false,
+            t_min: RenderSettings::default_t_min(),
+            max_distance: RenderSettings::default_max_distance(),
+        }
+    }
+
+    #[test]
+    fn phong_highlight_with_fractional_exponent_is_finite() {
+        let mut scene = Scene::new();
+        let shiny = Material::builder().color(Vec3::new(0.5, 0.2, 0.2)).specular(1.).shininess(0.5).build();
+        assert!(shiny.phong_exp.fract() != 0., "exponent {} should be fractional", shiny.phong_exp);
+        scene.add_sphere(Sphere::new(Vec3::new(0., 0., -3.), 1., shiny));
+        scene.add_light(PointLight::new(Vec3::new(2., 2., 2.), 1.));
+        let framebuffer = render(&scene, &Camera::new(FRAC_PI_2), &test_settings(32, 32));
+        assert!(framebuffer.pixels.iter().all(|p| p.x.is_finite() && p.y.is_finite() && p.z.is_finite()));
+    }
+
+    // A ground sphere, a scatter of small spheres (some mirrors, some straddling the edges of a 60° view) and a few
+    // out of view that only show up in reflections
+    fn test_scene() -> Scene {
+        let mut scene = Scene::new();
+        let mut rng = Rng::new(3);
+        for k in 0..24 {
+            let center = Vec3::new(rng.next_f32() * 8. - 4., rng.next_f32() * 5. - 2., -3. - rng.next_f32() * 8.);
+            let color = Vec3::new(rng.next_f32(), rng.next_f32(), rng.next_f32());
+            let mat = Material::builder().color(color).specular(0.5).phong_exp(40.).reflectance(if k % 5 == 0 { 0.6 } else { 0. }).build();
+            scene.add_sphere(Sphere::new(center, 0.2 + rng.next_f32() * 0.6, mat));
+        }
+        for center in [Vec3::new(0., 0., 4.), Vec3::new(-14., 0., -5.), Vec3::new(3., 12., -6.)] {
+            scene.add_sphere(Sphere::new(center, 1.5, Material::builder().color(Vec3::new(0.9, 0.6, 0.1)).build()));
+        }
+        let ground = Material::builder().color(Vec3::new(0.3, 0.3, 0.3)).reflectance(0.2).build()
+            .with_pattern(Pattern::Checker { even: Vec3::new(0.3, 0.3, 0.3), odd: Vec3::new(0.1, 0.1, 0.1), scale: 1. });
+        scene.add_sphere(Sphere::new(Vec3::new(0., -7_002.25, 0.), 7_000., ground));
+        scene.add_light(PointLight::new(Vec3::new(8., 8., 10.), 0.8));
+        scene.add_light(PointLight::new(Vec3::new(-3., 4., 5.), 0.65));
+        scene
+    }
+
+    fn unit_sphere_at(center: Vec3) -> Sphere {
+        Sphere::new(center, 1., Material::blank())
+    }
+
+    #[test]
+    fn sphere_uv_of_known_points() {
+        let c = Vec3::new(2., -1., -5.);
+        let sphere = Sphere::new(c, 2., Material::blank());
+        let uv = |dir: Vec3| sphere.uv(&(c + dir * 2.));
+        let (u, v) = uv(-Z);
+        assert!(u.rem_euclid(1.) < 1e-6 || u > 1. - 1e-6, "u at the -z seam is {}", u); // Either side of the seam
+        assert!((v - 0.5).abs() < 1e-6);
+        assert!((uv(-X).0 - 0.25).abs() < 1e-6);
+        assert!((uv(Z).0 - 0.5).abs() < 1e-6);
+        assert!((uv(X).0 - 0.75).abs() < 1e-6);
+        assert!(uv(Y).1.abs() < 1e-6);
+        assert!((uv(-Y).1 - 1.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sphere_uv_follows_orientation() {
+        // A quarter turn about +y takes the local -x meridian (u = 0.25) to world +z
+        let sphere = unit_sphere_at(Vec3::origin()).with_orientation(Mat3::from_axis_angle(&Y, FRAC_PI_2));
+        let (u, v) = sphere.uv(&Z);
+        assert!((u - 0.25).abs() < 1e-6 && (v - 0.5).abs() < 1e-6, "uv at +z is ({}, {})", u, v);
+        assert!(sphere.uv(&Y).1.abs() < 1e-3); // acos is steep at the pole, so rotation rounding shows up more there
+    }
+
+    #[test]
+    fn reflect_matches_hand_computed() {
+        let incident = Vec3::new(1., -1., 0.).normalized();
+        assert!(reflect(incident, Y).approx_eq(&Vec3::new(1., 1., 0.).normalized(), 1e-6));
+        // The normal's sign doesn't matter, and head-on comes straight back
+        assert!(reflect(incident, -Y).approx_eq(&Vec3::new(1., 1., 0.).normalized(), 1e-6));
+        assert!(reflect(-Z, Z).approx_eq(&Z, 1e-6));
+    }
+
+    // Ray from (offset, 0, 0) along -z toward a unit sphere at (0, 0, -5)
+    fn ray_past_sphere(offset: f32) -> (Sphere, Ray) {
+        (unit_sphere_at(Vec3::new(0., 0., -5.)), Ray { origin: Vec3::new(offset, 0., 0.), dir: -Z })
+    }
+
+    #[test]
+    fn build_grid_handles_empty_scenes_and_zero_resolution() {
+        let mut empty = Scene::new();
+        empty.build_grid(8);
+        assert!(empty.grid.is_none());
+
+        let mut scene = Scene::new();
+        scene.add_sphere(unit_sphere_at(Vec3::new(0., 0., -5.)));
+        scene.add_sphere(unit_sphere_at(Vec3::new(3., 0., -5.)));
+        scene.build_grid(0);
+        let hit = scene_hit(&Ray { origin: Vec3::origin(), dir: -Z }, &scene, 0.001, 1000.).unwrap();
+        assert_eq!(hit.index, 0);
+        assert!((hit.distance - 4.).abs() < 1e-5);
+    }
+
+    #[test]
+    fn load_ppm_reports_truncated_p6_data() {
+        let path = std::env::temp_dir().join(format!("rust_tracer_truncated_{}.ppm", std::process::id()));
+        for contents in [&b"P6\n2 1\n255"[..], b"P6\n2 1\n255\n", b"P6\n2 1\n255\n\x01\x02\x03"] {
+            std::fs::write(&path, contents).unwrap();
+            let err = Texture::load_ppm(path.to_str().unwrap()).err().expect("truncated file should fail");
+            assert!(err.to_string().contains("truncated pixel data"), "{}", err);
+        }
+        // Headers that would give an empty texture or overflow the pixel count
+        let bad_headers = [
+            (&b"P3\n0 0\n255\n"[..], "must be positive"),
+            (b"P3\n4 0\n255\n", "must be positive"),
+            (b"P6\n4294967296 4294967296\n255\n\x00", "too large"),
+            (b"P3\n18446744073709551615 2\n255\n", "too large"),
+        ];
+        for (contents, expected) in bad_headers {
+            std::fs::write(&path, contents).unwrap();
+            let err = Texture::load_ppm(path.to_str().unwrap()).err().expect("bad header should fail");
+            assert!(err.to_string().contains(expected), "{}", err);
+        }
+        std::fs::write(&path, b"P6\n2 1\n255\n\xff\x00\x00\x00\x00\xff").unwrap();
+        let texture = Texture::load_ppm(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(texture.texels, vec![X, Z]);
+    }
+
+    #[test]
+    fn environment_lookups_filter_over_the_beam() {
+        // Columns alternate black and white, so a wide enough footprint averages to mid gray
+        let texels = (0..64 * 32).map(|k| if k % 2 == 0 { Vec3::origin() } else { Vec3::new(1., 1., 1.) }).collect();
+        let background = Background::Environment(Texture::new(64, 32, texels));
+        let angle = ((10. + 0.5) / 64. - 0.5) * 2. * PI; // Center of column 10, a black one
+        let dir = Vec3::new(angle.sin(), 0., -angle.cos());
+        assert!(background.beam_color(&dir, 0.).x < 1e-3);
+        let wide = background.beam_color(&dir, 2. * PI * 16. / 64.).x; // Beam 16 texels wide
+        assert!((wide - 0.5).abs() < 0.05, "filtered to {}", wide);
+    }
+
+    #[test]
+    fn intersect_all_orders_crossings_nearest_first() {
+        let mut scene = Scene::new();
+        scene.add_sphere(unit_sphere_at(Vec3::new(0., 0., -10.)));
+        scene.add_sphere(Sphere::new(Vec3::new(0., 0., -5.), 2., Material::blank()));
+        scene.add_sphere(unit_sphere_at(Vec3::new(0., 0., 5.))); // Behind the ray
+        // A copy of sphere 0 crosses at exactly the same distances; ties go to the lower index
+        scene.add_sphere(unit_sphere_at(Vec3::new(0., 0., -10.)));
+        let ray = Ray { origin: Vec3::origin(), dir: -Z };
+        let hits: Vec<(usize, f32)> = scene.intersect_all(ray).map(|hit| (hit.index, hit.distance)).collect();
+        let expected = [(1, 3.), (1, 7.), (0, 9.), (3, 9.), (0, 11.), (3, 11.)];
+        assert_eq!(hits.len(), expected.len(), "{:?}", hits);
+        for ((i, t), (ei, et)) in hits.iter().zip(expected) {
+            assert!(*i == ei && (t - et).abs() < 1e-4, "{:?}", hits);
+        }
+        let first = scene.intersect_all(Ray { origin: Vec3::origin(), dir: -Z }).next().unwrap();
+        let nearest = scene_hit(&Ray { origin: Vec3::origin(), dir: -Z }, &scene, 0., f32::INFINITY).unwrap();
+        assert_eq!((first.index, first.distance), (nearest.index, nearest.distance));
+    }
+
+    #[test]
+    fn bump_map_with_missing_texture_is_skipped() {
+        let mut scene = Scene::new();
+        scene.add_sphere(Sphere::new(Vec3::new(0., 0., -5.), 1., Material::blank().with_bump(3, 1.)));
+        let hit = scene_hit(&Ray { origin: Vec3::origin(), dir: -Z }, &scene, 0.001, 1000.).unwrap();
+        assert!(hit.normal.approx_eq(&Z, 1e-6));
+    }
+
+    #[test]
+    fn grid_renders_the_same_image_as_brute_force() {
+        let mut scene = test_scene();
+        let (camera, settings) = (Camera::new(PI / 3.), test_settings(48, 36));
+        let brute_force = render(&scene, &camera, &settings).pixels;
+        for resolution in [1, 2, 3, 5, 8, 13, 32] {
+            scene.build_grid(resolution);
+            assert!(render(&scene, &camera, &settings).pixels == brute_force, "grid resolution {} differs", resolution);
+        }
+    }
+
+    #[test]
+    fn tile_order_does_not_change_the_image() {
+        let (scene, camera) = (test_scene(), Camera::new(PI / 3.));
+        for tile_size in [7, 16] {
+            let settings = |tile_order| RenderSettings { threads: 3, tile_size, tile_order, ..test_settings(48, 36) };
+            let raster = render(&scene, &camera, &settings(TileOrder::Raster)).pixels;
+            assert!(render(&scene, &camera, &settings(TileOrder::Morton)).pixels == raster, "tile size {}", tile_size);
+        }
+    }
+
+    #[test]
+    fn thread_count_does_not_change_the_image() {
+        // Path traced with random jitter and soft shadows, so every pixel draws plenty of random numbers
+        let mut scene = test_scene();
+        scene.lights[0] = PointLight::new(Vec3::new(8., 8., 10.), 0.8).with_radius(1.);
+        let camera = Camera::new(PI / 3.);
+        let settings = |threads, tile_size| RenderSettings {
+            mode: RenderMode::PathTraced, samples: 3, sampler: Sampler::Random, shadow_samples: 2, seed: 11, threads, tile_size,
+            edge_aa: Some(EdgeAa { threshold: 0.2, samples: 4 }),
+            ..test_settings(40, 30)
+        };
+        let serial = render(&scene, &camera, &settings(1, 16));
+        for (threads, tile_size) in [(3, 16), (4, 5), (8, 9)] {
+            let threaded = render(&scene, &camera, &settings(threads, tile_size));
+            assert!(threaded.pixels == serial.pixels && threaded.alpha == serial.alpha, "{} threads differ", threads);
+            assert_eq!(threaded.rays, serial.rays, "{} threads counted different rays", threads);
+        }
+        let reseeded = render(&scene, &camera, &RenderSettings { seed: 12, ..settings(1, 16) });
+        assert!(reseeded.pixels != serial.pixels, "the seed should matter");
+    }
+
+    #[test]
+    fn ray_counts_are_per_render() {
+        let scene = test_scene();
+        let camera = Camera::new(PI / 3.);
+        let settings = RenderSettings { samples: 2, shadow_samples: 3, ..test_settings(20, 15) };
+        let first = render(&scene, &camera, &settings).rays;
+        assert_eq!(first.primary, 20 * 15 * 2);
+        assert!(first.shadow > 0);
+        assert_eq!(render(&scene, &camera, &settings).rays, first, "counts should not carry over between renders");
+        let small = render(&scene, &camera, &RenderSettings { crop: Some((0, 0, 5, 5)), ..settings.clone() });
+        assert_eq!(small.rays.primary, 5 * 5 * 2);
+    }
+
+    #[test]
+    fn frustum_culling_does_not_change_the_image() {
+        let scene = test_scene();
+        let settings = test_settings(48, 36);
+        let turned = Mat3::from_euler(-0.2, 0.4, 0.1);
+        let cameras = [
+            Camera { orientation: turned, ..Camera::new(PI / 3.) },
+            Camera { distortion: 0.4, ..Camera::new(PI / 3.) },
+            Camera { distortion: -0.3, orientation: turned, ..Camera::new(PI / 3.) },
+            Camera { projection: Projection::Fisheye { fov: 0.9 * PI }, ..Camera::new(PI / 3.) },
+            Camera { projection: Projection::Fisheye { fov: 1.2 }, distortion: 0.2, orientation: turned, ..Camera::new(PI / 3.) },
+        ];
+        for camera in &cameras {
+            let visible = camera.visible_spheres(&scene.spheres, settings.width, settings.height);
+            assert!(visible.len() < scene.spheres.len(), "{:?} should cull something", camera.projection);
+            let spread = camera.pixel_angle(settings.height);
+            let culled = View { camera, visible: Some(visible), spread };
+            let all = View { camera, visible: None, spread };
+            let culled = render_serial(&scene, &culled, &settings, &mut Progress::default());
+            let all = render_serial(&scene, &all, &settings, &mut Progress::default());
+            assert!(culled.pixels == all.pixels, "culling changed the image for {:?} with distortion {}", camera.projection, camera.distortion);
+        }
+    }
+
+    #[cfg(feature = "packets")]
+    #[test]
+    fn packets_find_the_same_nearest_sphere_as_single_rays() {
+        let mut scene = test_scene();
+        scene.spheres.push(Sphere::new(scene.spheres[5].center, scene.spheres[5].radius, Material::default()));
+        let mut rng = Rng::new(8);
+        // Some rays aimed at sphere centers, some just inside or outside their silhouettes, some anywhere
+        let mut rays: Vec<Option<Ray>> = (0..400).map(|k| {
+            let origin = Vec3::new(rng.next_f32() * 2. - 1., rng.next_f32() * 2. - 1., rng.next_f32() * 2.);
+            let target = &scene.spheres[k % scene.spheres.len()];
+            let to_center = target.center - origin;
+            let aside = to_center.cross(&Y).normalized() * target.radius * (1. + (rng.next_f32() - 0.5) * 1e-3);
+            let dir = match k % 3 {
+                0 => to_center,
+                1 => to_center + aside,
+                _ => Vec3::new(rng.next_f32() - 0.5, rng.next_f32() - 0.5, -rng.next_f32()),
+            };
+            if k % 7 == 0 { None } else { Some(Ray { origin, dir }) }
+        }).collect();
+        rays.extend([None, None, None, None]);
+        let candidates: Vec<usize> = (0..scene.spheres.len()).rev().collect();
+        for (k, packet) in rays.chunks(4).enumerate() {
+            let packet = [0, 1, 2, 3].map(|lane| {
+                packet.get(lane).and_then(|ray| ray.as_ref()).map(|ray| Ray { origin: ray.origin, dir: ray.dir })
+            });
+            let nearest = RayPacket::new(&packet).nearest_sphere(&scene.spheres, &candidates, 0.001);
+            for (lane, ray) in packet.iter().enumerate() {
+                let expected = ray.as_ref().and_then(|ray| nearest_sphere(ray, &scene.spheres, candidates.iter().copied(), None, 0.001));
+                assert_eq!(nearest[lane], expected, "packet {} lane {}", k, lane);
+            }
+        }
+    }
+
+    #[cfg(feature = "packets")]
+    #[test]
+    fn packets_render_the_same_pixels_as_single_rays() {
+        let mut scene = test_scene();
+        scene.lights[0] = PointLight::new(Vec3::new(8., 8., 10.), 0.8).with_radius(1.);
+        let base = RenderSettings { shadow_samples: 2, seed: 5, ..test_settings(40, 30) };
+        let settings = [
+            base.clone(),
+            RenderSettings { samples: 4, sampler: Sampler::Random, max_sample_luminance: Some(0.5), ..base.clone() },
+            RenderSettings { mode: RenderMode::PathTraced, samples: 3, sampler: Sampler::Random, ..base.clone() },
+            RenderSettings { mode: RenderMode::AmbientOcclusion { samples: 3, distance: 2. }, ..base.clone() },
+            RenderSettings { mode: RenderMode::Normals, crop: Some((3, 4, 29, 21)), ..base.clone() },
+        ];
+        let cameras = [
+            Camera::new(PI / 3.),
+            Camera { distortion: 0.4, orientation: Mat3::from_euler(-0.2, 0.4, 0.1), ..Camera::new(PI / 3.) },
+            Camera { projection: Projection::Fisheye { fov: 0.9 * PI }, ..Camera::new(PI / 3.) },
+        ];
+        // Odd offset and size, so some blocks hang off the tile's edges
+        let tile = Tile { x: 1, y: 3, w: 37, h: 25 };
+        for settings in &settings {
+            for camera in &cameras {
+                let visible = camera.visible_spheres(&scene.spheres, settings.width, settings.height);
+                let view = View { camera, visible: Some(visible), spread: camera.pixel_angle(settings.height) };
+                let (mut packet_rays, mut single_rays) = (RayStats::default(), RayStats::default());
+                let packets = render_tile(&scene, &view, settings, &tile, &mut packet_rays);
+                let mut single = Vec::new();
+                for j in tile.y..tile.y + tile.h {
+                    for i in tile.x..tile.x + tile.w {
+                        single.push(if settings.in_crop(i, j) {
+                            render_pixel(&scene, &view, settings, i, j, &mut Rng::for_pixel(settings.seed, i, j), &mut single_rays)
+                        } else {
+                            (Vec3::origin(), 0.)
+                        });
+                    }
+                }
+                assert!(packets == single, "{:?} with {:?} differs", settings.mode, camera.projection);
+                assert_eq!(packet_rays, single_rays);
+            }
+        }
+    }
+
+    #[test]
+    fn vignette_darkens_only_toward_the_corners() {
+        let factor = |radius: f32, i, j| Vignette { strength: 0.5, radius }.factor(i, j, 100, 100);
+        for radius in [0.25, 0.5, 0.9] {
+            assert_eq!(factor(radius, 50, 50), 1., "radius {} darkened the center", radius);
+            assert!(factor(radius, 0, 0) < 1., "radius {} left the corner alone", radius);
+        }
+        for radius in [1., 1.5] {
+            assert_eq!(factor(radius, 50, 50), 1.);
+            assert_eq!(factor(radius, 0, 0), 1.);
+        }
+    }
+
+    #[test]
+    fn linear_fog_with_far_at_near_is_a_step() {
+        let (color, fog_color) = (Vec3::new(1., 0., 0.), Vec3::new(0., 0., 1.));
+        for far in [5., 4.] {
+            let fog = Fog::Linear { near: 5., far, color: fog_color };
+            assert_eq!(fog.apply(color, 4.9), color);
+            assert_eq!(fog.apply(color, 5.), fog_color);
+            assert_eq!(fog.apply(color, 50.), fog_color);
+        }
+        let fog = Fog::Linear { near: 5., far: 15., color: fog_color };
+        assert!(fog.apply(color, 10.).approx_eq(&Vec3::new(0.5, 0., 0.5), 1e-6));
+    }
+
+    #[test]
+    fn ray_roots_head_on() {
+        let (sphere, ray) = ray_past_sphere(0.);
+        let (t_0, t_1) = sphere.ray_roots(&ray).unwrap();
+        assert!((t_0 - 4.).abs() < 1e-6 && (t_1 - 6.).abs() < 1e-6, "roots ({}, {})", t_0, t_1);
+        assert_eq!(sphere.intersects_ray(&ray, 0.), Some(t_0));
+    }
+
+    #[test]
+    fn ray_roots_tangent_is_a_miss() {
+        let (sphere, ray) = ray_past_sphere(1.);
+        assert!(sphere.ray_roots(&ray).is_none());
+        assert!(sphere.intersects_ray(&ray, 0.).is_none());
+    }
+
+    #[test]
+    fn ray_roots_grazing() {
+        // Just inside the silhouette: a short chord centered on the sphere's depth
+        let (sphere, ray) = ray_past_sphere(0.99);
+        let (t_0, t_1) = sphere.ray_roots(&ray).unwrap();
+        let half_chord = (1f32 - 0.99 * 0.99).sqrt();
+        assert!((t_0 - (5. - half_chord)).abs() < 1e-5 && (t_1 - (5. + half_chord)).abs() < 1e-5, "roots ({}, {})", t_0, t_1);
+        // Within GRAZE_TOLERANCE of tangent is a miss
+        let (sphere, ray) = ray_past_sphere(1. - 1e-7);
+        assert!(sphere.ray_roots(&ray).is_none());
+    }
+
+    #[test]
+    fn ray_roots_miss() {
+        let (sphere, ray) = ray_past_sphere(1.5);
+        assert!(sphere.ray_roots(&ray).is_none());
+        // Pointing away, the line hits but the ray doesn't
+        let behind = Ray { origin: Vec3::origin(), dir: Z };
+        assert!(sphere.ray_roots(&behind).is_some());
+        assert!(sphere.intersects_ray(&behind, 0.).is_none());
+    }
+
+    // Nearer root of |origin + t dir - center| = radius and the half chord, worked in f64 for the same f32 inputs
+    fn nearer_root_f64(sphere: &Sphere, ray: &Ray) -> Option<(f64, f64)> {
+        let dir = ray.dir.normalized();
+        let oc = [0, 1, 2].map(|i| ray.origin[i] as f64 - sphere.center[i] as f64);
+        let b: f64 = (0..3).map(|i| oc[i] * dir[i] as f64).sum();
+        let c: f64 = oc.iter().map(|x| x * x).sum::<f64>() - (sphere.radius as f64).powi(2);
+        let discriminant = b * b - c;
+        if discriminant < 0. { None } else { Some((-b - discriminant.sqrt(), discriminant.sqrt())) }
+    }
+
+    // Largest relative error of ray_roots' nearer root against nearer_root_f64 over random rays from origin. Rays
+    // with a chord under a tenth of the diameter are left out: near tangent any f32 solve is ill-conditioned.
+    fn worst_root_error(sphere: &Sphere, origin: Vec3, dir: impl Fn(&mut Rng) -> Vec3) -> f64 {
+        let mut rng = Rng::new(7);
+        let mut worst: f64 = 0.;
+        for _ in 0..10_000 {
+            let ray = Ray { origin, dir: dir(&mut rng) };
+            if let (Some((t_0, _)), Some((exact, half_chord))) = (sphere.ray_roots(&ray), nearer_root_f64(sphere, &ray)) {
+                if half_chord < 0.1 * sphere.radius as f64 { continue; }
+                worst = worst.max((t_0 as f64 - exact).abs() / exact);
+            }
+        }
+        worst
+    }
+
+    #[test]
+    fn ray_roots_match_f64_for_small_and_huge_spheres() {
+        let small = Sphere::new(Vec3::new(0.3, -0.2, -5.), 1., Material::blank());
+        let small_error = worst_root_error(&small, Vec3::origin(),
+            |rng| Vec3::new(rng.next_f32() * 0.5 - 0.25, rng.next_f32() * 0.5 - 0.25, -1.).normalized());
+        // The 7000 unit ground seen from just above it
+        let ground = Sphere::new(Vec3::new(13.7, -7_002.25, 3.1), 7_000., Material::blank());
+        let ground_error = worst_root_error(&ground, Vec3::new(0.1, 0.37, 0.2),
+            |rng| Vec3::new(rng.next_f32() * 2. - 1., -rng.next_f32(), -1.).normalized());
+        assert!(small_error < 1e-5, "unit sphere roots off by {:e}", small_error);
+        assert!(ground_error < 2e-5, "ground roots off by {:e}", ground_error);
+    }
+}
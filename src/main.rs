@@ -1,249 +1,232 @@
 use std::fs::File;
 use std::io::prelude::*;
-use std::ops;
-
-#[derive(Debug, Copy, Clone)]
-struct Vec3 {
-    x: f32,
-    y: f32,
-    z: f32
-}
-
-impl Vec3 {
-    fn new(x: f32, y: f32, z: f32) -> Self {
-        Vec3 { x, y, z }
-    }
-
-    fn mag(&self) -> f32 {
-        (*self * *self).sqrt()
-    }
-
-    fn normalized(&self) -> Self {
-        let mag = self.mag();
-        return *self * (1. / mag);
-    }
-
-    fn reflect_on(&self, n: &Vec3) -> Self {
-        *n * 2. * (*self * *n)  - *self
-    }
-
-    fn origin() -> Self {
-        Vec3::new(0., 0., 0.)
-    }
-}
-
-impl ops::Mul<Vec3> for Vec3 {
-    type Output = f32;
-    fn mul(self, v: Vec3) -> f32 {
-        self.x * v.x + self.y * v.y + self.z * v.z // Dot product
-    }
-}
-
-impl ops::Mul<f32> for Vec3 {
-    type Output = Vec3;
-    fn mul(self, f: f32) -> Self {
-        Vec3 { x: f * self.x, y: f * self.y, z: f * self.z }
-    }
-}
-
-impl ops::Add<Vec3> for Vec3 {
-    type Output = Vec3;
-    fn add(self, v: Vec3) -> Self {
-        Vec3 { x: self.x + v.x, y: self.y + v.y, z: self.z + v.z }
-    }
-}
-
-impl ops::Sub<Vec3> for Vec3 {
-    type Output = Vec3;
-    fn sub(self, v: Vec3) -> Self {
-        Vec3 { x: self.x - v.x, y: self.y - v.y, z: self.z - v.z }
-    }
-}
-
-impl ops::Neg for Vec3 {
-    type Output = Self;
-    fn neg(self) -> Self {
-        Vec3 { x: self.x * -1., y: self.y * -1., z: self.z * -1. }
-    }
-}
-
-struct Ray {
-    origin: Vec3,
-    dir: Vec3,
-}
-
-struct RaycastHit {
-    point: Vec3,
-    normal: Vec3,
-    mat: Material,
-}
-
-struct PointLight {
-    origin: Vec3,
-    intensity: f32,
-}
-
-#[derive(Debug, Copy, Clone)]
-struct Material { 
-    color: Vec3,
-    phong_exp: f32,
-    phong_const: f32,
-    diffuse_const: f32,
-    ambient_const: f32,
-    reflectance: f32,
-}
-
-impl Material {
-    fn blank() -> Self {
-        Material { color: Vec3::new(0., 0., 0.), phong_exp: 0., phong_const: 0., diffuse_const: 0., ambient_const: 0., reflectance: 0. }
-    }
-
-    fn new(color: Vec3, phong_exp: f32, phong_const: f32, diffuse_const: f32, ambient_const: f32, reflectance: f32) -> Self {
-        Material { color, phong_exp, phong_const, diffuse_const, ambient_const, reflectance }
-    }
-}
-
-struct Sphere {
-    center: Vec3,
-    radius: f32,
-    mat: Material,
-}
-
-impl Sphere {
-    fn new(center: Vec3, radius: f32, mat: Material) -> Self {
-        Sphere { center, radius, mat }
-    }
-
-    // Returns intersection distance along ray or None for no intersection
-    fn intersects_ray(&self, ray: &Ray) -> Option<f32> {
-        let l = self.center - ray.origin;
-        let pld = l * ray.dir.normalized();
-        let d_2 = l * l - pld * pld;
-        if d_2 > self.radius.powi(2) { return None; }
-        let td = (self.radius.powi(2) - d_2).sqrt();
-        let t_0 = pld - td;
-        let t_1 = pld + td;
-        if t_0 > 0. { Some(t_0) } else if t_1 > 0. { Some(t_1) } else { None }
-    }
-}
-
-struct Scene {
-    spheres: Vec<Sphere>,
-    lights: Vec<PointLight>,
-}
-
-// Returns RaycastHit with info or None if there is no intersection
-fn scene_hit(ray: &Ray, scene: &Scene, max_dist: f32) -> Option<RaycastHit> {
-    let mut min_dist = f32::MAX;
-    let mut surface_mat = Material::blank();
-    let mut surface_point = Vec3::origin();
-    let mut surface_normal = Vec3::origin();
-
-    for sphere in &scene.spheres {
-        if let Some(cur_dist) = sphere.intersects_ray(ray) {
-            if cur_dist < min_dist {
-                min_dist = cur_dist;
-                surface_mat = sphere.mat;
-                surface_point = ray.origin + ray.dir * cur_dist;
-                surface_normal = (surface_point - sphere.center).normalized();
-            }
-        }
-    }
-    if min_dist < max_dist { // Max draw distance
-        return Some(RaycastHit {
-            point: surface_point,
-            normal: surface_normal,
-            mat: surface_mat,
-        });
-    }
-    return None;
-}
-
-// Cast a ray and return the pixel color as a Vec3
-fn raycast(ray: &Ray, scene: &Scene, depth: i32) -> Vec3 {
-    if depth < 4 {
-        if let Some(hit_info) = scene_hit(ray, scene, 1000.) {
-            let surface_mat = hit_info.mat;
-            let surface_point = hit_info.point;
-            let surface_normal = hit_info.normal;
-            let mut diffuse_intensity = 0.;
-            let mut specular_intensity = 0.;
-
-            // Reflect
-            let reflect_dir = -ray.dir.reflect_on(&surface_normal).normalized();
-            let reflect_point = surface_point + surface_normal * 0.001;
-            let reflect_color = raycast(&Ray { origin: reflect_point, dir: reflect_dir }, &scene, depth + 1);  
-
-            for light in &scene.lights {
-                let light_vec = light.origin - surface_point;
-                let light_dir = light_vec.normalized();
-                let light_dist = light_vec.mag();
-
-                // Check if point is in shadow
-                let shadow_point = surface_point + surface_normal * 0.001;
-                if let Some(_hit) = scene_hit(&Ray { origin: shadow_point, dir: light_dir }, scene, light_dist) {
-                    continue;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use rust_tracer::{
+    post_process, render, render_stereo, side_by_side, write_png, Camera, Encoding, Material, Pattern, PointLight, RayStats,
+    RenderMode, RenderSettings, Sampler, Scene, Sphere, TileOrder, ToneMap, Vec3,
+};
+
+fn demo_settings() -> RenderSettings {
+    let samples = 1;
+    RenderSettings {
+        mode: RenderMode::Shaded,
+        width: 500,
+        height: 500,
+        max_depth: 4,
+        gi_bounces: 3,
+        samples,
+        sampler: Sampler::default_for(samples),
+        seed: 0,
+        max_sample_luminance: None,
+        shadow_samples: 16,
+        blur_radius: 0,
+        tone_map: ToneMap::Clip,
+        encoding: Encoding::Srgb,
+        exposure: 0.,
+        vignette: None,
+        threads: 0,
+        tile_size: 16,
+        tile_order: TileOrder::Raster,
+        edge_aa: None,
+        crop: None,
+        transparent_background: false,
+        t_min: RenderSettings::default_t_min(),
+        max_distance: RenderSettings::default_max_distance(),
+    }
+}
+
+// Everything besides the scene that decides how a render comes out, as written by --save-settings and read by --settings
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SettingsFile {
+    settings: RenderSettings,
+    camera: Camera,
+}
+
+// Reads a settings file over `defaults`. Fields the file leaves out keep their default, so a file can hold just the
+// few settings it changes, except that setting samples without a sampler picks the sampler default_for suits, as
+// --samples does. Unknown fields and mistyped values are errors naming the file.
+fn load_settings(path: &str, defaults: SettingsFile) -> std::io::Result<SettingsFile> {
+    let invalid = |e: serde_json::Error| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {}", path, e));
+    let text = std::fs::read_to_string(path).map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    let file: serde_json::Value = serde_json::from_str(&text).map_err(invalid)?;
+    let sets = |field: &str| file.get("settings").and_then(|s| s.get(field)).is_some();
+    let default_sampler = sets("samples") && !sets("sampler");
+    let mut merged = serde_json::to_value(&defaults)?;
+    match (merged.as_object_mut(), file) {
+        (Some(merged), serde_json::Value::Object(file)) => {
+            for (section, fields) in file {
+                match (merged.get_mut(&section).and_then(|s| s.as_object_mut()), fields) {
+                    (Some(section), serde_json::Value::Object(fields)) => section.extend(fields),
+                    (_, fields) => { merged.insert(section, fields); }
                 }
-
-                diffuse_intensity += (light_dir * surface_normal).max(0.) * light.intensity;
-                specular_intensity += (light_dir.reflect_on(&surface_normal) * ray.dir).min(0.).powf(surface_mat.phong_exp) * light.intensity;
             }
-
-            let diffuse_color = surface_mat.color * diffuse_intensity * surface_mat.diffuse_const;
-            let specular_color = Vec3::new(1., 1., 1.) * specular_intensity * surface_mat.phong_const;
-            let ambient_color = surface_mat.color * surface_mat.ambient_const;
-            let reflection = reflect_color * surface_mat.reflectance;
-            return diffuse_color + specular_color + ambient_color + reflection;
         }
+        (_, file) => merged = file,
+    }
+    let mut loaded: SettingsFile = serde_json::from_value(merged).map_err(invalid)?;
+    if default_sampler { loaded.settings.sampler = Sampler::default_for(loaded.settings.samples); }
+    if loaded.settings.width == 0 || loaded.settings.height == 0 {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: width and height must be positive", path)));
+    }
+    Ok(loaded)
+}
+
+// The argument following flag `name`, or None when the flag isn't given
+fn flag_value(name: &str) -> std::io::Result<Option<String>> {
+    let mut args = std::env::args().skip_while(|arg| arg != name);
+    if args.next().is_none() { return Ok(None); }
+    args.next().map(Some)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} needs an argument", name)))
+}
+
+// Positive integer from environment variable `name`, or None when it isn't set
+fn env_dimension(name: &str) -> std::io::Result<Option<usize>> {
+    match std::env::var(name) {
+        Ok(value) => match value.trim().parse::<usize>() {
+            Ok(n) if n > 0 => Ok(Some(n)),
+            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("{} must be a positive integer, got \"{}\"", name, value))),
+        },
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{}: {}", name, e))),
+    }
+}
+
+fn reference_scene() -> Scene {
+    let m_blue = Material::builder().color(Vec3::new(0.1, 0.1, 0.4)).specular(0.4).phong_exp(40.).build();
+    let m_red = Material::builder().color(Vec3::new(0.7, 0.02, 0.05)).diffuse(1.2).ambient(0.2)
+        .specular(1.).phong_exp(250.).build();
+    let m_mirror = Material::builder().color(Vec3::new(1., 1., 1.)).diffuse(0.).ambient(0.)
+        .specular(1.).phong_exp(1500.).reflectance(0.75).build();
+    let ground_checker = Pattern::Checker { even: Vec3::new(0.25, 0.25, 0.25), odd: Vec3::new(0.12, 0.12, 0.12), scale: 1. };
+    let m_ground = Material::builder().color(Vec3::new(0.2, 0.2, 0.2)).reflectance(0.17).build().with_pattern(ground_checker);
+
+    let mut scene = Scene::new();
+    scene.add_sphere(Sphere::new(Vec3::new(0., -1.25, -5.), 1., m_red));
+    scene.add_sphere(Sphere::new(Vec3::new(-2., -0.75, -7.), 1.2, m_mirror));
+    scene.add_sphere(Sphere::new(Vec3::new(0.8, 0.45, -4.), 1., m_blue));
+    scene.add_sphere(Sphere::new(Vec3::new(0., -7_002.25, 0.), 7_000., m_ground));
+    scene.add_light(PointLight::new(Vec3::new(8., 8., 10.), 0.8));
+    scene.add_light(PointLight::new(Vec3::new(-3., 4., 5.), 0.65));
+    scene.build_grid(8); // Leave grid as None to intersect by brute force
+    scene
+}
+
+// Renders the reference scene with pinned settings a few times and reports the time and ray throughput,
+// so numbers are comparable across commits. Single threaded to keep the core count out of the comparison.
+// Runs once through the grid and once by brute force, which is where the packets feature traces 2x2 packets,
+// so building with and without it compares packet and single-ray camera rays.
+fn bench() {
+    const RUNS: u32 = 5;
+    let settings = RenderSettings {
+        mode: RenderMode::Shaded,
+        width: 400,
+        height: 300,
+        max_depth: 4,
+        samples: 4,
+        sampler: Sampler::Halton,
+        seed: 1,
+        threads: 1,
+        ..demo_settings()
+    };
+    let camera = Camera::new(std::f32::consts::PI / 3.);
+    let grid = reference_scene();
+    let brute_force = Scene { grid: None, ..reference_scene() };
+    let packets = if cfg!(feature = "packets") { "2x2 packets" } else { "single rays" };
+
+    for (name, scene) in [("grid", &grid), (&format!("brute force, {}", packets), &brute_force)] {
+        let start = Instant::now();
+        let mut rays = 0;
+        for _ in 0..RUNS {
+            rays += render(scene, &camera, &settings).rays.total();
+        }
+        let secs = start.elapsed().as_secs_f64();
+        println!("{}: {} runs of {}x{} at {} spp: {:.3}s total, {:.3}s per run, {} rays, {:.0} rays/s", name,
+            RUNS, settings.width, settings.height, settings.samples, secs, secs / RUNS as f64, rays, rays as f64 / secs);
     }
-
-    let height = ray.dir.y;
-    return Vec3::new(height, height, height); // Background color
 }
 
 fn main() -> std::io::Result<()> {
-    let width = 500;
-    let height = 500;
-    let fov = std::f32::consts::PI / 3.;
-
-    // Scene construction
-    let m_blue = Material::new(Vec3::new(0.1, 0.1, 0.4), 40., 0.4, 1., 0.1, 0.0);
-    let m_red = Material::new(Vec3::new(0.7, 0.02, 0.05), 250., 1., 1.2, 0.2, 0.);
-    let m_mirror = Material::new(Vec3::new(1., 1., 1.), 1500., 1., 0., 0., 0.75);
-    let m_ground = Material::new(Vec3::new(0.2, 0.2, 0.2), 0., 0., 1., 0.1, 0.17);
-    let mut spheres = Vec::new();
-    spheres.push(Sphere::new(Vec3::new(0., -1.25, -5.), 1., m_red));
-    spheres.push(Sphere::new(Vec3::new(-2., -0.75, -7.), 1.2, m_mirror));
-    spheres.push(Sphere::new(Vec3::new(0.8, 0.45, -4.), 1., m_blue));
-    spheres.push(Sphere::new(Vec3::new(0., -7_002.25, 0.), 7_000., m_ground));
-    let mut lights = Vec::new();
-    lights.push(PointLight { origin: Vec3::new(8., 8., 10.), intensity: 0.8 });
-    lights.push(PointLight { origin: Vec3::new(-3., 4., 5.), intensity: 0.65 });
-    let scene = Scene { spheres: spheres, lights: lights };
-
-    let mut data = Vec::new();
-    for j in 0..height {
-        for i in 0..width {
-            let w = i as f32;
-            let h = j as f32;
-            let x = (fov / 2.).tan() * (2. * (w + 0.5) / width as f32 - 1.) * (width as f32 / height as f32);
-            let y = (fov / 2.).tan() * -(2. * (h + 0.5) / height as f32 - 1.);
-            let z = -1.;
-            let dir = Vec3::new(x, y, z).normalized();
-            data.push(raycast(&Ray { origin: Vec3::origin(), dir: dir }, &scene, 0));
-        }
-    }
-
+    if std::env::args().any(|arg| arg == "--bench") {
+        bench();
+        return Ok(());
+    }
+
+    // Layered: built-in defaults, then a --settings FILE, then flags and environment variables.
+    // TRACER_WIDTH and TRACER_HEIGHT override the resolution, e.g. for scripted renders.
+    let defaults = SettingsFile { settings: demo_settings(), camera: Camera::new(std::f32::consts::PI / 3.) };
+    let SettingsFile { mut settings, camera } = match flag_value("--settings")? {
+        Some(path) => load_settings(&path, defaults)?,
+        None => defaults,
+    };
+    let alpha = std::env::args().any(|arg| arg == "--alpha");
+    if std::env::args().any(|arg| arg == "--transparent") { settings.transparent_background = true; }
+    if settings.transparent_background && !alpha {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+            "a transparent background needs an output with an alpha channel; pass --alpha too"));
+    }
+    // --samples N sets the samples per pixel, with the sampler default_for picks for N
+    if let Some(n) = flag_value("--samples")? {
+        settings.samples = match n.parse::<u32>() {
+            Ok(n) if n > 0 => n,
+            _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("--samples must be a positive integer, got \"{}\"", n))),
+        };
+        settings.sampler = Sampler::default_for(settings.samples);
+    }
+    settings.width = env_dimension("TRACER_WIDTH")?.unwrap_or(settings.width);
+    settings.height = env_dimension("TRACER_HEIGHT")?.unwrap_or(settings.height);
+    let (width, height) = (settings.width, settings.height);
+    let scene = reference_scene();
+    // --stereo IPD renders a side-by-side pair, left eye on the left, with the eyes IPD scene units apart
+    let stereo = match flag_value("--stereo")? {
+        Some(ipd) => Some(ipd.parse::<f32>().map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput,
+            format!("--stereo needs an eye separation, got \"{}\": {}", ipd, e)))?),
+        None => None,
+    };
+
+    // --save-settings FILE records the settings and camera of this render as JSON before rendering it
+    let config = SettingsFile { settings, camera };
+    if let Some(path) = flag_value("--save-settings")? {
+        std::fs::write(path, serde_json::to_string_pretty(&config)? + "\n")?;
+    }
+    let SettingsFile { settings, camera } = config;
+
+    let start = Instant::now();
+    let frames = match stereo {
+        Some(ipd) => Vec::from(render_stereo(&scene, &camera, &settings, ipd)),
+        None => vec![render(&scene, &camera, &settings)],
+    };
+    let frames: Vec<_> = frames.into_iter().map(|frame| post_process(frame, &settings)).collect();
+    let out_width = width * frames.len();
+
+    // Summed over both eyes for a stereo pair
+    let mut rays = RayStats::default();
+    for frame in &frames {
+        rays += frame.rays;
+    }
+    eprintln!("Rendered {}x{} in {:.3}s: {} primary, {} shadow, {} reflection, {} transmission, {} diffuse rays",
+        out_width, height, start.elapsed().as_secs_f64(),
+        rays.primary, rays.shadow, rays.reflection, rays.transmission, rays.diffuse);
+
+    // --alpha writes coverage as transparency, which PPM can't hold, so it switches the output to PNG
+    if alpha {
+        let mut file = File::create("render.png")?;
+        let images: Vec<_> = frames.iter().map(|frame| frame.to_ldr_rgba(&settings)).collect();
+        return write_png(&mut file, out_width, height, &side_by_side(&images, width));
+    }
+    let images: Vec<_> = frames.iter().map(|frame| frame.to_ldr(&settings)).collect();
     let mut buffer = String::new();
-    buffer.push_str(&format!("P3\n{} {}\n255\n", width, height));
-    for d in data {
-        buffer.push_str(&format!("{} {} {}\n", 
-            ((d.x.min(1.) * 255.) as u8), 
-            ((d.y.min(1.) * 255.) as u8), 
-            ((d.z.min(1.) * 255.) as u8)));
+    buffer.push_str(&format!("P3\n{} {}\n255\n", out_width, height));
+    for [r, g, b] in side_by_side(&images, width) {
+        buffer.push_str(&format!("{} {} {}\n", r, g, b));
     }
     let mut file = File::create("render.ppm")?;
-    file.write_all(&buffer.as_bytes())?;
+    file.write_all(buffer.as_bytes())?;
 
     Ok(())
 }